@@ -9,10 +9,12 @@
 //! new memory settings.
 
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
 use std::path::PathBuf;
 
+use flate2::{Compression, write::GzEncoder};
+
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
@@ -29,6 +31,16 @@ fn main() {
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
 
+    // The dashboard is served gzipped, which makes it small enough to keep in flash
+    let html = fs::read("dashboard/index.html").unwrap();
+    let mut encoder = GzEncoder::new(
+        File::create(out.join("dashboard.html.gz")).unwrap(),
+        Compression::best(),
+    );
+    encoder.write_all(&html).unwrap();
+    encoder.finish().unwrap();
+    println!("cargo:rerun-if-changed=dashboard/index.html");
+
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tlink-rp.x");
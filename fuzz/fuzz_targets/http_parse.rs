@@ -0,0 +1,27 @@
+// Feeds arbitrary bytes to the HTTP parser, which sees whatever a client on the network sends.
+// Run with `cargo +nightly fuzz run http_parse` from the repository root.
+#![no_main]
+
+use hydroponic_automation_embassy::http::{self, ParseError};
+use libfuzzer_sys::fuzz_target;
+
+// The size of an HTTP worker's request buffer
+const MAX_LEN: usize = 2048;
+
+fuzz_target!(|data: &[u8]| {
+    let data = &data[..data.len().min(MAX_LEN)];
+    match http::parse(data, MAX_LEN) {
+        Ok(req) => {
+            // The worker moves anything after `len` to the front for the next request
+            assert!(req.len <= data.len());
+            assert!(req.body.len() <= req.len);
+            let _ = req.keep_alive();
+            let _ = req.header("content-type");
+            let _ = http::query_param(req.query, "secs");
+        }
+        Err(ParseError::Incomplete) => assert!(data.len() < MAX_LEN),
+        Err(e) => {
+            let _ = e.status();
+        }
+    }
+});
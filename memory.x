@@ -1,6 +1,7 @@
 MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
+    /* The last two 4K sectors hold the config store (see src/config.rs) */
+    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100 - 8K
 
     /* Pick one of the two options for RAM layout     */
 
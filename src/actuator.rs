@@ -0,0 +1,363 @@
+use core::convert::Infallible;
+use embassy_futures::select::{Either, select};
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    watch::{Receiver, Watch},
+};
+use embassy_time::{Duration, Timer};
+use embedded_hal::{digital::OutputPin, pwm::SetDutyCycle};
+use log::*;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Anything the controller switches on and off: pumps, valves, relays and lights.
+/// Control logic should be written against this rather than a concrete driver.
+#[allow(async_fn_in_trait)]
+pub trait Actuator {
+    /// Sets the output level in percent, 0 switches it off
+    async fn set_level(&mut self, percent: u8) -> Result<(), ActuatorError>;
+
+    /// The level last set, in percent
+    fn level(&self) -> u8;
+
+    async fn set_on(&mut self, on: bool) -> Result<(), ActuatorError> {
+        self.set_level(if on { 100 } else { 0 }).await
+    }
+
+    fn is_on(&self) -> bool {
+        self.level() > 0
+    }
+
+    /// Runs at `percent` for `duration`, then switches off.
+    /// The actuator is switched off even if the run ends early with an error.
+    async fn run_for(&mut self, percent: u8, duration: Duration) -> Result<(), ActuatorError> {
+        let result = match self.set_level(percent).await {
+            Ok(()) => {
+                Timer::after(duration).await;
+                Ok(())
+            }
+            Err(e) => Err(e),
+        };
+        self.set_level(0).await?;
+        result
+    }
+
+    /// The fault that stopped the actuator, it won't start again until this is cleared
+    fn fault(&self) -> Option<MotorFault> {
+        None
+    }
+
+    fn clear_fault(&mut self) {}
+}
+
+// One receiver per `Interlocked` actuator
+const MAX_INTERLOCKED: usize = 8;
+
+// True while everything that moves water has to stay off
+static EMERGENCY_STOP: Watch<CriticalSectionRawMutex, bool, MAX_INTERLOCKED> =
+    Watch::new_with(false);
+
+/// Stops every `Interlocked` actuator and keeps them off until `clear_emergency_stop`
+pub fn emergency_stop() {
+    EMERGENCY_STOP.sender().send(true);
+}
+
+pub fn clear_emergency_stop() {
+    EMERGENCY_STOP.sender().send(false);
+}
+
+pub fn emergency_stopped() -> bool {
+    EMERGENCY_STOP.try_get().unwrap_or(false)
+}
+
+/// Wraps an actuator so it refuses to switch on during an emergency stop, and cuts any
+/// timed run short when one is raised. Pumps and valves should always be wrapped in this.
+pub struct Interlocked<A: Actuator> {
+    inner: A,
+    stop: Receiver<'static, CriticalSectionRawMutex, bool, MAX_INTERLOCKED>,
+}
+
+impl<A: Actuator> Interlocked<A> {
+    pub fn new(inner: A) -> Self {
+        Interlocked {
+            inner,
+            stop: EMERGENCY_STOP
+                .receiver()
+                .expect("Too many interlocked actuators"),
+        }
+    }
+
+    pub fn inner_mut(&mut self) -> &mut A {
+        &mut self.inner
+    }
+
+    /// Waits for the next emergency stop and switches the actuator off. For tasks that leave
+    /// an actuator on between runs.
+    pub async fn wait_for_stop(&mut self) {
+        self.stop.changed_and(|stopped| *stopped).await;
+        if let Err(e) = self.inner.set_level(0).await {
+            error!("Failed to stop actuator: {}", e);
+        }
+    }
+}
+
+impl<A: Actuator> Actuator for Interlocked<A> {
+    async fn set_level(&mut self, percent: u8) -> Result<(), ActuatorError> {
+        if percent > 0 && emergency_stopped() {
+            return Err(ActuatorError::EmergencyStop);
+        }
+        self.inner.set_level(percent).await
+    }
+
+    fn level(&self) -> u8 {
+        self.inner.level()
+    }
+
+    async fn run_for(&mut self, percent: u8, duration: Duration) -> Result<(), ActuatorError> {
+        if emergency_stopped() {
+            return Err(ActuatorError::EmergencyStop);
+        }
+        let stop = self.stop.changed_and(|stopped| *stopped);
+        match select(self.inner.run_for(percent, duration), stop).await {
+            Either::First(result) => result,
+            Either::Second(_) => {
+                // The run was dropped part way, so the actuator may still be on
+                self.inner.set_level(0).await?;
+                Err(ActuatorError::EmergencyStop)
+            }
+        }
+    }
+
+    fn fault(&self) -> Option<MotorFault> {
+        self.inner.fault()
+    }
+
+    fn clear_fault(&mut self) {
+        self.inner.clear_fault()
+    }
+}
+
+/// A relay or MOSFET on a single GPIO, either fully on or off
+pub struct Relay<P: OutputPin<Error = Infallible>> {
+    pin: P,
+    // Many relay boards switch on when their input is pulled low
+    active_low: bool,
+    on: bool,
+}
+
+impl<P: OutputPin<Error = Infallible>> Relay<P> {
+    /// Starts switched off
+    pub fn new(pin: P, active_low: bool) -> Self {
+        let mut relay = Relay {
+            pin,
+            active_low,
+            on: false,
+        };
+        relay.switch(false);
+        relay
+    }
+
+    fn switch(&mut self, on: bool) {
+        let Ok(()) = if on != self.active_low {
+            self.pin.set_high()
+        } else {
+            self.pin.set_low()
+        };
+        self.on = on;
+    }
+}
+
+impl<P: OutputPin<Error = Infallible>> Actuator for Relay<P> {
+    /// Any level above 0 switches the relay on
+    async fn set_level(&mut self, percent: u8) -> Result<(), ActuatorError> {
+        self.switch(percent > 0);
+        Ok(())
+    }
+
+    fn level(&self) -> u8 {
+        if self.on { 100 } else { 0 }
+    }
+}
+
+/// A solenoid valve switched by a relay. On means open, whichever way the valve is built.
+pub struct SolenoidValve<P: OutputPin<Error = Infallible>> {
+    relay: Relay<P>,
+    // Normally open valves close when powered
+    normally_open: bool,
+}
+
+impl<P: OutputPin<Error = Infallible>> SolenoidValve<P> {
+    /// Starts in the valve's unpowered position
+    pub fn new(relay: Relay<P>, normally_open: bool) -> Self {
+        SolenoidValve {
+            relay,
+            normally_open,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.relay.on != self.normally_open
+    }
+}
+
+impl<P: OutputPin<Error = Infallible>> Actuator for SolenoidValve<P> {
+    /// Any level above 0 opens the valve
+    async fn set_level(&mut self, percent: u8) -> Result<(), ActuatorError> {
+        self.relay.switch((percent > 0) != self.normally_open);
+        Ok(())
+    }
+
+    fn level(&self) -> u8 {
+        if self.is_open() { 100 } else { 0 }
+    }
+}
+
+/// A dimmable load on a PWM output, like an LED driver's dimming input
+pub struct PwmDimmer<PWM: SetDutyCycle> {
+    pwm: PWM,
+    level: u8,
+}
+
+impl<PWM: SetDutyCycle> PwmDimmer<PWM> {
+    /// Starts switched off
+    pub fn new(mut pwm: PWM) -> Self {
+        // A failure here shows up again on the first `set_level`
+        pwm.set_duty_cycle_fully_off().ok();
+        PwmDimmer { pwm, level: 0 }
+    }
+}
+
+impl<PWM: SetDutyCycle> Actuator for PwmDimmer<PWM> {
+    async fn set_level(&mut self, percent: u8) -> Result<(), ActuatorError> {
+        let percent = percent.min(100);
+        self.pwm
+            .set_duty_cycle_percent(percent)
+            .map_err(|_| ActuatorError::Hardware)?;
+        self.level = percent;
+        Ok(())
+    }
+
+    fn level(&self) -> u8 {
+        self.level
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum MotorFault {
+    Stalled,
+    NoLoad,
+    SenseFailed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Error)]
+pub enum ActuatorError {
+    #[error("Hardware error")]
+    Hardware,
+    #[error("Actuator fault: {0:?}")]
+    Fault(MotorFault),
+    #[error("Emergency stop")]
+    EmergencyStop,
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::{block_on, join::join};
+
+    use super::*;
+
+    // Stays on until it's switched off, so a run only ends through the interlock
+    struct MockActuator {
+        level: u8,
+    }
+
+    impl Actuator for MockActuator {
+        async fn set_level(&mut self, percent: u8) -> Result<(), ActuatorError> {
+            self.level = percent;
+            Ok(())
+        }
+
+        fn level(&self) -> u8 {
+            self.level
+        }
+
+        async fn run_for(&mut self, percent: u8, _duration: Duration) -> Result<(), ActuatorError> {
+            self.set_level(percent).await?;
+            core::future::pending().await
+        }
+    }
+
+    struct MockPin {
+        high: bool,
+    }
+
+    impl embedded_hal::digital::ErrorType for MockPin {
+        type Error = Infallible;
+    }
+
+    impl OutputPin for MockPin {
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.high = false;
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            self.high = true;
+            Ok(())
+        }
+    }
+
+    // The emergency stop is shared by every interlock, so it's only ever raised in this test
+    #[test]
+    fn emergency_stop_forces_interlocked_actuators_off() {
+        block_on(async {
+            let mut pump = Interlocked::new(MockActuator { level: 0 });
+            let mut valve = Interlocked::new(MockActuator { level: 0 });
+            valve.set_on(true).await.unwrap();
+
+            let (result, ()) = join(pump.run_for(50, Duration::from_secs(60)), async {
+                emergency_stop()
+            })
+            .await;
+            assert_eq!(result, Err(ActuatorError::EmergencyStop));
+            assert_eq!(pump.level(), 0);
+
+            // Left on between runs, it's switched off by whoever waits for the stop
+            assert!(valve.is_on());
+            valve.wait_for_stop().await;
+            assert!(!valve.is_on());
+
+            assert_eq!(pump.set_level(100).await, Err(ActuatorError::EmergencyStop));
+            assert_eq!(
+                pump.run_for(100, Duration::from_secs(1)).await,
+                Err(ActuatorError::EmergencyStop)
+            );
+            assert_eq!(valve.set_on(true).await, Err(ActuatorError::EmergencyStop));
+            assert_eq!(pump.level(), 0);
+            assert!(!valve.is_on());
+            // Switching off is always allowed
+            assert_eq!(pump.set_level(0).await, Ok(()));
+
+            clear_emergency_stop();
+            assert_eq!(pump.set_level(100).await, Ok(()));
+            assert_eq!(pump.level(), 100);
+        });
+    }
+
+    #[test]
+    fn normally_open_valve_on_active_low_relay() {
+        block_on(async {
+            let relay = Relay::new(MockPin { high: false }, true);
+            assert!(relay.pin.high);
+            let mut valve = SolenoidValve::new(relay, true);
+            assert!(valve.is_open());
+
+            valve.set_on(false).await.unwrap();
+            assert!(!valve.relay.pin.high);
+            assert_eq!(valve.level(), 0);
+
+            valve.set_on(true).await.unwrap();
+            assert!(valve.relay.pin.high);
+            assert!(valve.is_open());
+        });
+    }
+}
@@ -0,0 +1,389 @@
+// JSON views of the machine state and config changes, served by the REST API
+use embassy_time::{Duration, Instant};
+use heapless::{Deque, Vec};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock,
+    config::{Config, PUMP_COUNT},
+    hardware::{
+        actuator::MotorFault,
+        level::{LevelBand, WaterLevel},
+    },
+    history::{HISTORY_INTERVAL_SECS, HISTORY_LEN, Sample},
+    leak::Emergency,
+    maintenance::maintenance_remaining,
+    state::{DosingLockout, EcState, HydroponicState, PhState},
+};
+
+/// Where a reading sits relative to its configured bounds
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Classification {
+    Unknown,
+    Good,
+    High,
+    Low,
+}
+
+/// A sensor value, when it was taken and whether it can be trusted
+#[derive(Debug, Serialize)]
+pub struct Reading {
+    pub value: Option<f32>,
+    pub status: Classification,
+    // Unix time of the reading, null until the clock has been synced
+    pub timestamp: Option<u64>,
+    pub age_secs: Option<u64>,
+    // There is a reading and it's recent enough to act on
+    pub healthy: bool,
+}
+
+impl Reading {
+    fn new(
+        value: Option<f32>,
+        status: Classification,
+        updated: Option<Instant>,
+        max_age: Duration,
+    ) -> Reading {
+        Reading {
+            value,
+            status,
+            timestamp: updated.and_then(clock::unix_time_at),
+            age_secs: updated.map(|t| t.elapsed().as_secs()),
+            healthy: value.is_some() && updated.is_some_and(|t| t.elapsed() <= max_age),
+        }
+    }
+
+    pub fn ph(state: &HydroponicState, config: &Config) -> Reading {
+        let (value, status) = match state.ph {
+            PhState::Unknown => (None, Classification::Unknown),
+            PhState::Good(v) => (Some(v), Classification::Good),
+            PhState::High(v) => (Some(v), Classification::High),
+            PhState::Low(v) => (Some(v), Classification::Low),
+        };
+        let max_age = Duration::from_secs(config.sensor_max_age_secs as u64);
+        Reading::new(value, status, state.ph_updated, max_age)
+    }
+
+    pub fn ec(state: &HydroponicState, config: &Config) -> Reading {
+        let (value, status) = match state.ec {
+            EcState::Unknown => (None, Classification::Unknown),
+            EcState::Good(v) => (Some(v), Classification::Good),
+            EcState::High(v) => (Some(v), Classification::High),
+            EcState::Low(v) => (Some(v), Classification::Low),
+        };
+        let max_age = Duration::from_secs(config.sensor_max_age_secs as u64);
+        Reading::new(value, status, state.ec_updated, max_age)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct WaterLevelReading {
+    pub band: LevelBand,
+    // Only known with a continuous sensor
+    pub percent: Option<f32>,
+    pub litres: Option<f32>,
+    pub timestamp: Option<u64>,
+    pub age_secs: Option<u64>,
+    pub healthy: bool,
+}
+
+impl WaterLevelReading {
+    pub fn new(state: &HydroponicState, config: &Config) -> WaterLevelReading {
+        let WaterLevel {
+            band,
+            percent,
+            litres,
+        } = state.water_level;
+        let updated = state.water_level_updated;
+        // Float switch edges update the level between readings, so a missed interval or two is fine
+        let max_age = Duration::from_secs(config.water_level_interval_secs as u64 * 2);
+        WaterLevelReading {
+            band,
+            percent,
+            litres,
+            timestamp: updated.and_then(clock::unix_time_at),
+            age_secs: updated.map(|t| t.elapsed().as_secs()),
+            healthy: band != LevelBand::Unknown && updated.is_some_and(|t| t.elapsed() <= max_age),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PumpStatus {
+    // mL dispensed since the bottle was refilled
+    pub dispensed_ml: f32,
+    pub fault: Option<MotorFault>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LightStatus {
+    pub percent: u8,
+    pub overridden: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TopUpStatus {
+    pub active: bool,
+    pub last_litres: f32,
+    pub timed_out: bool,
+}
+
+/// Everything the controller knows, for `/api/state`
+#[derive(Debug, Serialize)]
+pub struct MachineStatus {
+    pub uptime_secs: u64,
+    // Unix time, null until the clock has been synced
+    pub time: Option<u64>,
+    // Time left before automation resumes, null when it's running
+    pub maintenance_secs_left: Option<u64>,
+    pub ph: Reading,
+    pub ec: Reading,
+    pub water_level: WaterLevelReading,
+    pub emergency: Option<Emergency>,
+    pub dosing_lockout: Option<DosingLockout>,
+    // In the order of the `config::*_PUMP` indices
+    pub pumps: [PumpStatus; PUMP_COUNT],
+    pub light: LightStatus,
+    pub irrigation_pump_on: bool,
+    pub top_up: TopUpStatus,
+}
+
+impl MachineStatus {
+    pub fn new(state: &HydroponicState, config: &Config) -> MachineStatus {
+        MachineStatus {
+            uptime_secs: Instant::now().as_secs(),
+            time: clock::unix_time(),
+            maintenance_secs_left: maintenance_remaining().map(|left| left.as_secs()),
+            ph: Reading::ph(state, config),
+            ec: Reading::ec(state, config),
+            water_level: WaterLevelReading::new(state, config),
+            emergency: state.emergency,
+            dosing_lockout: state.dosing_lockout,
+            pumps: core::array::from_fn(|i| PumpStatus {
+                dispensed_ml: state.pump_dispensed_ml[i],
+                fault: state.pump_faults[i],
+            }),
+            light: LightStatus {
+                percent: state.light_percent,
+                overridden: state.light_overridden,
+            },
+            irrigation_pump_on: state.irrigation_pump_on,
+            top_up: TopUpStatus {
+                active: state.topping_up,
+                last_litres: state.last_top_up_litres,
+                timed_out: state.top_up_timed_out,
+            },
+        }
+    }
+}
+
+/// The recent readings, one array per sensor, oldest first
+#[derive(Debug, Serialize)]
+pub struct History {
+    pub interval_secs: u64,
+    pub ph_centi: Vec<Option<u16>, HISTORY_LEN>,
+    pub ec: Vec<Option<u16>, HISTORY_LEN>,
+    pub level_percent: Vec<Option<u8>, HISTORY_LEN>,
+}
+
+impl History {
+    pub fn new(samples: &Deque<Sample, HISTORY_LEN>) -> History {
+        History {
+            interval_secs: HISTORY_INTERVAL_SECS,
+            ph_centi: samples.iter().map(|s| s.ph_centi).collect(),
+            ec: samples.iter().map(|s| s.ec).collect(),
+            level_percent: samples.iter().map(|s| s.level_percent).collect(),
+        }
+    }
+}
+
+/// Error body for any failed API request
+#[derive(Debug, Serialize)]
+pub struct ApiError<'a> {
+    pub error: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConfigUpdated {
+    // The new settings are saved but only take effect after a reboot
+    pub reboot_required: bool,
+}
+
+/// Part of the config sent in a request body, only the fields that are given get changed
+pub trait ConfigPatch {
+    const REBOOT_REQUIRED: bool = false;
+
+    fn apply(&self, config: &mut Config);
+}
+
+fn set<T>(field: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *field = value;
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ThresholdsPatch {
+    pub ph_upper: Option<f32>,
+    pub ph_lower: Option<f32>,
+    pub ph_target: Option<f32>,
+    pub ec_upper: Option<f32>,
+    pub ec_lower: Option<f32>,
+    pub ec_target: Option<f32>,
+}
+
+impl ConfigPatch for ThresholdsPatch {
+    fn apply(&self, config: &mut Config) {
+        set(&mut config.ph_upper, self.ph_upper);
+        set(&mut config.ph_lower, self.ph_lower);
+        set(&mut config.ph_target, self.ph_target);
+        set(&mut config.ec_upper, self.ec_upper);
+        set(&mut config.ec_lower, self.ec_lower);
+        set(&mut config.ec_target, self.ec_target);
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IntervalsPatch {
+    pub ec_interval_secs: Option<u32>,
+    pub ph_interval_secs: Option<u32>,
+    pub water_level_interval_secs: Option<u32>,
+    pub sensor_max_age_secs: Option<u32>,
+    pub float_debounce_ms: Option<u32>,
+}
+
+impl ConfigPatch for IntervalsPatch {
+    fn apply(&self, config: &mut Config) {
+        set(&mut config.ec_interval_secs, self.ec_interval_secs);
+        set(&mut config.ph_interval_secs, self.ph_interval_secs);
+        set(
+            &mut config.water_level_interval_secs,
+            self.water_level_interval_secs,
+        );
+        set(&mut config.sensor_max_age_secs, self.sensor_max_age_secs);
+        set(&mut config.float_debounce_ms, self.float_debounce_ms);
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DosingPatch {
+    pub ph_ml_per_unit: Option<f32>,
+    pub ph_max_dose_ml: Option<f32>,
+    pub ph_max_daily_ml: Option<f32>,
+    pub ph_mixing_secs: Option<u32>,
+    pub reservoir_litres: Option<f32>,
+    pub nutrient_ec_per_ml_per_l: Option<f32>,
+    pub nutrient_ratio_a: Option<f32>,
+    pub nutrient_ratio_b: Option<f32>,
+    pub nutrient_ratio_c: Option<f32>,
+    pub ec_max_dose_ml: Option<f32>,
+    pub ec_max_daily_ml: Option<f32>,
+    pub ec_part_mixing_secs: Option<u32>,
+    pub ec_mixing_secs: Option<u32>,
+    pub dose_settle_secs: Option<u32>,
+    pub pump_max_run_secs: Option<u32>,
+    pub pump_max_daily_ml: Option<f32>,
+    pub pump_min_interval_secs: Option<u32>,
+}
+
+impl ConfigPatch for DosingPatch {
+    fn apply(&self, config: &mut Config) {
+        set(&mut config.ph_ml_per_unit, self.ph_ml_per_unit);
+        set(&mut config.ph_max_dose_ml, self.ph_max_dose_ml);
+        set(&mut config.ph_max_daily_ml, self.ph_max_daily_ml);
+        set(&mut config.ph_mixing_secs, self.ph_mixing_secs);
+        set(&mut config.reservoir_litres, self.reservoir_litres);
+        set(
+            &mut config.nutrient_ec_per_ml_per_l,
+            self.nutrient_ec_per_ml_per_l,
+        );
+        set(&mut config.nutrient_ratio_a, self.nutrient_ratio_a);
+        set(&mut config.nutrient_ratio_b, self.nutrient_ratio_b);
+        set(&mut config.nutrient_ratio_c, self.nutrient_ratio_c);
+        set(&mut config.ec_max_dose_ml, self.ec_max_dose_ml);
+        set(&mut config.ec_max_daily_ml, self.ec_max_daily_ml);
+        set(&mut config.ec_part_mixing_secs, self.ec_part_mixing_secs);
+        set(&mut config.ec_mixing_secs, self.ec_mixing_secs);
+        set(&mut config.dose_settle_secs, self.dose_settle_secs);
+        set(&mut config.pump_max_run_secs, self.pump_max_run_secs);
+        set(&mut config.pump_max_daily_ml, self.pump_max_daily_ml);
+        set(
+            &mut config.pump_min_interval_secs,
+            self.pump_min_interval_secs,
+        );
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SchedulesPatch {
+    pub light_on_mins: Option<u32>,
+    pub light_photoperiod_mins: Option<u32>,
+    pub light_ramp_mins: Option<u32>,
+    pub light_max_percent: Option<u8>,
+    pub utc_offset_mins: Option<i32>,
+    pub irrigation_day_on_mins: Option<u32>,
+    pub irrigation_day_period_mins: Option<u32>,
+    pub irrigation_night_on_mins: Option<u32>,
+    pub irrigation_night_period_mins: Option<u32>,
+    pub irrigation_max_on_mins: Option<u32>,
+}
+
+impl ConfigPatch for SchedulesPatch {
+    fn apply(&self, config: &mut Config) {
+        set(&mut config.light_on_mins, self.light_on_mins);
+        set(
+            &mut config.light_photoperiod_mins,
+            self.light_photoperiod_mins,
+        );
+        set(&mut config.light_ramp_mins, self.light_ramp_mins);
+        set(&mut config.light_max_percent, self.light_max_percent);
+        set(&mut config.utc_offset_mins, self.utc_offset_mins);
+        set(
+            &mut config.irrigation_day_on_mins,
+            self.irrigation_day_on_mins,
+        );
+        set(
+            &mut config.irrigation_day_period_mins,
+            self.irrigation_day_period_mins,
+        );
+        set(
+            &mut config.irrigation_night_on_mins,
+            self.irrigation_night_on_mins,
+        );
+        set(
+            &mut config.irrigation_night_period_mins,
+            self.irrigation_night_period_mins,
+        );
+        set(
+            &mut config.irrigation_max_on_mins,
+            self.irrigation_max_on_mins,
+        );
+    }
+}
+
+/// Addresses are arrays of 4 numbers, like `[10, 0, 0, 21]`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkPatch {
+    pub ip_address: Option<[u8; 4]>,
+    pub prefix_len: Option<u8>,
+    pub gateway: Option<[u8; 4]>,
+    pub ntp_server: Option<[u8; 4]>,
+}
+
+impl ConfigPatch for NetworkPatch {
+    // The network stack is only configured at boot
+    const REBOOT_REQUIRED: bool = true;
+
+    fn apply(&self, config: &mut Config) {
+        set(&mut config.ip_address, self.ip_address);
+        set(&mut config.prefix_len, self.prefix_len);
+        set(&mut config.gateway, self.gateway);
+        set(&mut config.ntp_server, self.ntp_server);
+    }
+}
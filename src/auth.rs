@@ -0,0 +1,88 @@
+// Who is allowed to use the API. Tokens are set at build time in .env, like the Wi-Fi password:
+//   API_READ_TOKEN  can read the state. Without it anyone on the network can.
+//   API_ADMIN_TOKEN can also change the config and drive the actuators. Without it nobody can.
+// A token is sent as "Authorization: Bearer <token>", or as the password for HTTP Basic auth
+// (any user name) so a browser can log in.
+use dotenv_proc::dotenv_option;
+
+use crate::{
+    base64,
+    http::{Method, Request},
+};
+
+const API_READ_TOKEN: Option<&str> = dotenv_option!("API_READ_TOKEN");
+const API_ADMIN_TOKEN: Option<&str> = dotenv_option!("API_ADMIN_TOKEN");
+
+// Longest decoded "user:password" accepted for Basic auth
+const MAX_CREDENTIALS_LEN: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    ReadOnly,
+    Admin,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthError {
+    // No credentials, or wrong ones
+    Unauthorized,
+    // Valid credentials, but not allowed to do this
+    Forbidden,
+}
+
+/// Anything that changes something needs an admin
+pub fn required_role(method: Method) -> Role {
+    match method {
+        Method::Get | Method::Head | Method::Options => Role::ReadOnly,
+        Method::Post | Method::Put | Method::Delete => Role::Admin,
+    }
+}
+
+/// Checks the request's credentials allow it
+pub fn authorize(req: &Request<'_>) -> Result<Role, AuthError> {
+    let required = required_role(req.method);
+    let role = match req.header("Authorization") {
+        Some(header) => role_for(header).ok_or(AuthError::Unauthorized)?,
+        // Reads are open when there's no read token
+        None if required == Role::ReadOnly && API_READ_TOKEN.is_none() => Role::ReadOnly,
+        None => return Err(AuthError::Unauthorized),
+    };
+    if role < required {
+        return Err(AuthError::Forbidden);
+    }
+    Ok(role)
+}
+
+// The role the token in an Authorization header belongs to
+fn role_for(header: &str) -> Option<Role> {
+    let (scheme, credentials) = header.trim().split_once(' ')?;
+    let credentials = credentials.trim();
+    if scheme.eq_ignore_ascii_case("Bearer") {
+        role_for_token(credentials.as_bytes())
+    } else if scheme.eq_ignore_ascii_case("Basic") {
+        let decoded = base64::decode::<MAX_CREDENTIALS_LEN>(credentials.as_bytes())?;
+        let colon = decoded.iter().position(|b| *b == b':')?;
+        role_for_token(&decoded[colon + 1..])
+    } else {
+        None
+    }
+}
+
+fn role_for_token(token: &[u8]) -> Option<Role> {
+    // An empty token in .env would otherwise match an empty password
+    let matches = |expected: Option<&str>| {
+        expected.is_some_and(|e| !e.is_empty() && constant_time_eq(e.as_bytes(), token))
+    };
+    if matches(API_ADMIN_TOKEN) {
+        Some(Role::Admin)
+    } else if matches(API_READ_TOKEN) {
+        Some(Role::ReadOnly)
+    } else {
+        None
+    }
+}
+
+// Takes the same time wherever the first difference is, so a token can't be guessed byte by byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -0,0 +1,101 @@
+// Standard base64 (RFC 4648) with padding, for HTTP headers
+use heapless::{String, Vec};
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn value(c: u8) -> Option<u32> {
+    ALPHABET.iter().position(|a| *a == c).map(|v| v as u32)
+}
+
+/// Decodes `input`, returning `None` if it isn't valid base64 or doesn't fit in `N` bytes
+pub fn decode<const N: usize>(input: &[u8]) -> Option<Vec<u8, N>> {
+    if !input.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut out = Vec::new();
+    let chunks = input.len() / 4;
+    for (i, chunk) in input.chunks(4).enumerate() {
+        // Padding is only allowed at the very end
+        let padding = chunk.iter().rev().take_while(|c| **c == b'=').count();
+        if padding > 2 || (padding > 0 && i + 1 != chunks) {
+            return None;
+        }
+        let mut bits = 0;
+        for c in &chunk[..4 - padding] {
+            bits = bits << 6 | value(*c)?;
+        }
+        bits <<= 6 * padding as u32;
+        let bytes = [(bits >> 16) as u8, (bits >> 8) as u8, bits as u8];
+        out.extend_from_slice(&bytes[..3 - padding]).ok()?;
+    }
+    Some(out)
+}
+
+/// Encodes `input`, returning `None` if it doesn't fit in `N` bytes
+pub fn encode<const N: usize>(input: &[u8]) -> Option<String<N>> {
+    let mut out = String::new();
+    for chunk in input.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0, |bits, (i, b)| bits | ((*b as u32) << (16 - 8 * i)));
+        // n bytes need n + 1 characters, the rest is padding
+        for i in 0..4 {
+            let c = if i <= chunk.len() {
+                ALPHABET[(bits >> (18 - 6 * i)) as usize & 0x3f]
+            } else {
+                b'='
+            };
+            out.push(c as char).ok()?;
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The test vectors from RFC 4648
+    const VECTORS: [(&str, &str); 7] = [
+        ("", ""),
+        ("f", "Zg=="),
+        ("fo", "Zm8="),
+        ("foo", "Zm9v"),
+        ("foob", "Zm9vYg=="),
+        ("fooba", "Zm9vYmE="),
+        ("foobar", "Zm9vYmFy"),
+    ];
+
+    #[test]
+    fn encodes_the_standard_vectors() {
+        for (plain, encoded) in VECTORS {
+            assert_eq!(encode::<8>(plain.as_bytes()).unwrap(), encoded);
+        }
+    }
+
+    #[test]
+    fn decodes_the_standard_vectors() {
+        for (plain, encoded) in VECTORS {
+            assert_eq!(decode::<6>(encoded.as_bytes()).unwrap(), plain.as_bytes());
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        // Not a multiple of 4
+        assert_eq!(decode::<8>(b"Zg="), None);
+        // Padding before the end
+        assert_eq!(decode::<8>(b"Zg==Zm9v"), None);
+        assert_eq!(decode::<8>(b"Z==="), None);
+        // Not in the alphabet
+        assert_eq!(decode::<8>(b"Zm9-"), None);
+        assert_eq!(decode::<8>(b"Zm 9"), None);
+    }
+
+    #[test]
+    fn refuses_output_that_does_not_fit() {
+        assert_eq!(decode::<5>(b"Zm9vYmFy"), None);
+        assert_eq!(encode::<7>(b"foobar"), None);
+    }
+}
@@ -0,0 +1,1088 @@
+// Persistent configuration, stored as key/value entries in the last two sectors of flash.
+// Saves alternate between the two sectors so a power cut mid-write always leaves one good copy.
+use crc::{CRC_32_ISO_HDLC, Crc};
+use embassy_rp::{
+    flash::{Blocking, ERASE_SIZE, Flash, PAGE_SIZE},
+    peripherals::FLASH,
+};
+use embassy_sync::{
+    blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex},
+    mutex::Mutex,
+};
+use log::*;
+use thiserror::Error;
+
+use crate::{
+    hardware::{current_sense::CurrentThresholds, dosing_pump::PumpCalibration},
+    leak::Emergency,
+};
+
+/// Size of the flash chip on the Pico W
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+/// Bump this whenever the meaning of an existing key changes and add a step to `migrate`
+pub const CONFIG_VERSION: u16 = 2;
+
+// Must stay in sync with the space reserved at the end of FLASH in memory.x
+const CONFIG_OFFSET: u32 = (FLASH_SIZE - 2 * ERASE_SIZE) as u32;
+const MAGIC: u32 = 0x4859_4452; // "HYDR"
+// magic (4) + version (2) + payload length (2) + sequence (4) + crc (4)
+const HEADER_LEN: usize = 16;
+// Leaves plenty of room for new keys while staying a whole number of pages
+const RECORD_LEN: usize = 4 * PAGE_SIZE;
+const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+const MINS_PER_DAY: u32 = 24 * 60;
+
+// Index of each dosing pump in `Config::pump_calibration`
+pub const PH_UP_PUMP: usize = 0;
+pub const PH_DOWN_PUMP: usize = 1;
+pub const PART_A_PUMP: usize = 2;
+pub const PART_B_PUMP: usize = 3;
+pub const PART_C_PUMP: usize = 4;
+pub const PUMP_COUNT: usize = 5;
+
+pub type ConfigFlash = Flash<'static, FLASH, Blocking, FLASH_SIZE>;
+pub type SharedConfigStore = Mutex<NoopRawMutex, ConfigStore>;
+
+/// The configuration currently in use by the tasks
+pub static CONFIG: Mutex<CriticalSectionRawMutex, Config> = Mutex::new(Config::defaults());
+
+/// Changes the config in use and persists it
+pub async fn update(
+    store: &SharedConfigStore,
+    change: impl FnOnce(&mut Config),
+) -> Result<Config, ConfigError> {
+    let mut config = CONFIG.lock().await;
+    let mut updated = *config;
+    change(&mut updated);
+    // Nothing changes if the result doesn't make sense or can't be saved, so what's in use
+    // always matches what's in flash
+    updated.validate().map_err(ConfigError::Invalid)?;
+    store.lock().await.save(&updated)?;
+    *config = updated;
+    Ok(updated)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Config {
+    pub ip_address: [u8; 4],
+    pub prefix_len: u8,
+    pub gateway: [u8; 4],
+    pub ph_upper: f32,
+    pub ph_lower: f32,
+    pub ec_upper: f32,
+    pub ec_lower: f32,
+    pub ec_address: u8,
+    pub ph_address: u8,
+    pub ec_interval_secs: u32,
+    pub ph_interval_secs: u32,
+    pub water_level_interval_secs: u32,
+    pub ph_target: f32,
+    // mL of adjuster per 1.0 of pH error
+    pub ph_ml_per_unit: f32,
+    pub ph_max_dose_ml: f32,
+    pub ph_max_daily_ml: f32,
+    pub ph_mixing_secs: u32,
+    pub ec_target: f32,
+    pub reservoir_litres: f32,
+    // EC rise (uS/cm) from 1mL of concentrate per litre of reservoir
+    pub nutrient_ec_per_ml_per_l: f32,
+    // Parts A/B/C are dosed in this ratio, a ratio of 0 skips that part
+    pub nutrient_ratio_a: f32,
+    pub nutrient_ratio_b: f32,
+    pub nutrient_ratio_c: f32,
+    pub ec_max_dose_ml: f32,
+    pub ec_max_daily_ml: f32,
+    pub ec_part_mixing_secs: u32,
+    pub ec_mixing_secs: u32,
+    // Minimum wait after any dose before anything else is dosed
+    pub dose_settle_secs: u32,
+    pub sensor_max_age_secs: u32,
+    // Hard limits applied to every dosing pump, whatever the controllers ask for
+    pub pump_max_run_secs: u32,
+    pub pump_max_daily_ml: f32,
+    pub pump_min_interval_secs: u32,
+    // Motor current sensing, see `CurrentThresholds`
+    pub current_ma_per_count: f32,
+    pub over_current_ma: u32,
+    pub no_load_ma: u32,
+    // Grow light schedule, in minutes of local time
+    pub light_on_mins: u32,
+    pub light_photoperiod_mins: u32,
+    pub light_ramp_mins: u32,
+    pub light_max_percent: u8,
+    // Whether the schedule last had the light on, followed after a reset until the clock syncs
+    pub light_was_on: bool,
+    // Local time = UTC + offset, adjust by hand for daylight saving
+    pub utc_offset_mins: i32,
+    pub ntp_server: [u8; 4],
+    // Flood/drain cycles: the pump runs for `on` minutes at the start of every `period`.
+    // Day and night follow the light schedule. `on` >= `period` runs continuously for NFT.
+    pub irrigation_day_on_mins: u32,
+    pub irrigation_day_period_mins: u32,
+    pub irrigation_night_on_mins: u32,
+    pub irrigation_night_period_mins: u32,
+    // Longest the pump may run without a break, 0 disables the check
+    pub irrigation_max_on_mins: u32,
+    // Reservoir top-up, the valve closes when the high level switch trips or after the max fill time
+    pub top_up_max_fill_secs: u32,
+    // Flow through the fill valve, only used to log the volume added
+    pub top_up_litres_per_min: f32,
+    // Wait after a top-up before EC is read again
+    pub top_up_mixing_secs: u32,
+    // 0 for a rectangular tank, 1 for a cylinder where the width is the diameter
+    pub tank_shape: u8,
+    pub tank_length_mm: f32,
+    pub tank_width_mm: f32,
+    pub tank_height_mm: f32,
+    // Heights of the level marks above the tank floor, where the float switches are mounted
+    pub level_low_mm: f32,
+    pub level_normal_mm: f32,
+    pub level_high_mm: f32,
+    pub level_overflow_mm: f32,
+    // Raw ADC readings from an analog level sensor at empty and at the tank height
+    pub level_analog_empty_counts: u32,
+    pub level_analog_full_counts: u32,
+    // Height of an ultrasonic sensor above the tank floor
+    pub level_sensor_mount_mm: f32,
+    // A float switch has to hold still this long before a change counts, ripples make them chatter
+    pub float_debounce_ms: u32,
+    pub pump_calibration: [PumpCalibration; PUMP_COUNT],
+    // mL each pump has dispensed since its bottle was refilled
+    pub pump_dispensed_ml: [f32; PUMP_COUNT],
+    // Set by the leak task and kept through resets, so only `/emergency/reset` clears it
+    pub emergency: Option<Emergency>,
+}
+
+impl Config {
+    pub const fn defaults() -> Config {
+        Config {
+            ip_address: [10, 0, 0, 21],
+            prefix_len: 24,
+            gateway: [10, 0, 0, 1],
+            ph_upper: 7.4,
+            ph_lower: 5.3,
+            ec_upper: 1200.0,
+            ec_lower: 1000.0,
+            // TODO: SET TO CORRECT ADDRS
+            ec_address: 0x20,
+            ph_address: 0x21,
+            ec_interval_secs: 180,
+            ph_interval_secs: 180,
+            water_level_interval_secs: 600,
+            ph_target: 6.0,
+            ph_ml_per_unit: 5.0,
+            ph_max_dose_ml: 10.0,
+            ph_max_daily_ml: 50.0,
+            ph_mixing_secs: 900,
+            ec_target: 1100.0,
+            reservoir_litres: 40.0,
+            nutrient_ec_per_ml_per_l: 150.0,
+            nutrient_ratio_a: 1.0,
+            nutrient_ratio_b: 1.0,
+            nutrient_ratio_c: 0.0,
+            ec_max_dose_ml: 20.0,
+            ec_max_daily_ml: 100.0,
+            ec_part_mixing_secs: 120,
+            ec_mixing_secs: 900,
+            dose_settle_secs: 600,
+            sensor_max_age_secs: 600,
+            pump_max_run_secs: 30,
+            pump_max_daily_ml: 100.0,
+            pump_min_interval_secs: 300,
+            current_ma_per_count: 0.8,
+            over_current_ma: 800,
+            no_load_ma: 30,
+            light_on_mins: 360,
+            light_photoperiod_mins: 960,
+            light_ramp_mins: 30,
+            light_max_percent: 100,
+            light_was_on: false,
+            utc_offset_mins: 0,
+            ntp_server: [162, 159, 200, 1],
+            irrigation_day_on_mins: 15,
+            irrigation_day_period_mins: 120,
+            irrigation_night_on_mins: 15,
+            irrigation_night_period_mins: 240,
+            irrigation_max_on_mins: 30,
+            top_up_max_fill_secs: 300,
+            top_up_litres_per_min: 4.0,
+            top_up_mixing_secs: 300,
+            tank_shape: 0,
+            tank_length_mm: 600.0,
+            tank_width_mm: 400.0,
+            tank_height_mm: 300.0,
+            level_low_mm: 100.0,
+            level_normal_mm: 150.0,
+            level_high_mm: 220.0,
+            level_overflow_mm: 270.0,
+            level_analog_empty_counts: 400,
+            level_analog_full_counts: 3600,
+            level_sensor_mount_mm: 350.0,
+            float_debounce_ms: 1000,
+            pump_calibration: [PumpCalibration::new(1.0, 100); PUMP_COUNT],
+            pump_dispensed_ml: [0.0; PUMP_COUNT],
+            emergency: None,
+        }
+    }
+
+    /// Checks the settings that can be changed at runtime make sense, and together
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.prefix_len > 32 {
+            return Err("prefix_len must be at most 32");
+        }
+
+        let ph_range = 0.0..=14.0;
+        if !ph_range.contains(&self.ph_lower)
+            || !ph_range.contains(&self.ph_upper)
+            || self.ph_lower >= self.ph_upper
+        {
+            return Err("ph_lower must be below ph_upper, both within 0-14");
+        }
+        if !(self.ph_lower..=self.ph_upper).contains(&self.ph_target) {
+            return Err("ph_target must be between ph_lower and ph_upper");
+        }
+        if !non_negative(self.ec_lower) || self.ec_lower >= self.ec_upper {
+            return Err("ec_lower must be below ec_upper and not negative");
+        }
+        if !(self.ec_lower..=self.ec_upper).contains(&self.ec_target) {
+            return Err("ec_target must be between ec_lower and ec_upper");
+        }
+
+        if self.ec_interval_secs == 0
+            || self.ph_interval_secs == 0
+            || self.water_level_interval_secs == 0
+        {
+            return Err("sensor intervals must be at least 1 second");
+        }
+        // Otherwise every reading is stale before the next one and dosing never runs
+        if self.sensor_max_age_secs <= self.ec_interval_secs.max(self.ph_interval_secs) {
+            return Err("sensor_max_age_secs must be longer than the EC and pH intervals");
+        }
+
+        let amounts = [
+            self.ph_ml_per_unit,
+            self.ph_max_dose_ml,
+            self.ph_max_daily_ml,
+            self.nutrient_ratio_a,
+            self.nutrient_ratio_b,
+            self.nutrient_ratio_c,
+            self.ec_max_dose_ml,
+            self.ec_max_daily_ml,
+            self.pump_max_daily_ml,
+        ];
+        if !amounts.into_iter().all(non_negative) {
+            return Err("dosing amounts and ratios can't be negative");
+        }
+        if !positive(self.reservoir_litres) || !positive(self.nutrient_ec_per_ml_per_l) {
+            return Err("reservoir_litres and nutrient_ec_per_ml_per_l must be above 0");
+        }
+        if self.nutrient_ratio_a + self.nutrient_ratio_b + self.nutrient_ratio_c <= 0.0 {
+            return Err("at least one nutrient ratio must be above 0");
+        }
+        if self.pump_max_run_secs == 0 {
+            return Err("pump_max_run_secs must be at least 1 second");
+        }
+        // A zero flow rate would make every dose take forever
+        if self
+            .pump_calibration
+            .iter()
+            .any(|c| !positive(c.ml_per_sec) || !(1..=100).contains(&c.duty_percent))
+        {
+            return Err("pump calibrations need a flow rate above 0 and a duty of 1-100%");
+        }
+        if !self.pump_dispensed_ml.into_iter().all(non_negative) {
+            return Err("pump_dispensed_ml can't be negative");
+        }
+        if !positive(self.current_ma_per_count) || self.no_load_ma >= self.over_current_ma {
+            return Err(
+                "current_ma_per_count must be above 0 and no_load_ma below over_current_ma",
+            );
+        }
+
+        if self.light_on_mins >= MINS_PER_DAY || self.light_photoperiod_mins > MINS_PER_DAY {
+            return Err("light_on_mins and light_photoperiod_mins must fit in a day");
+        }
+        if self.light_max_percent > 100 {
+            return Err("light_max_percent must be at most 100");
+        }
+        if self.utc_offset_mins.abs() > 14 * 60 {
+            return Err("utc_offset_mins must be within 14 hours");
+        }
+        if self.irrigation_day_period_mins == 0 || self.irrigation_night_period_mins == 0 {
+            return Err("irrigation periods must be at least 1 minute");
+        }
+        if self.top_up_max_fill_secs == 0 || !non_negative(self.top_up_litres_per_min) {
+            return Err(
+                "top_up_max_fill_secs must be at least 1 second, the flow rate not negative",
+            );
+        }
+
+        if self.tank_shape > 1 {
+            return Err("tank_shape must be 0 (rectangular) or 1 (cylinder)");
+        }
+        if !positive(self.tank_length_mm)
+            || !positive(self.tank_width_mm)
+            || !positive(self.tank_height_mm)
+        {
+            return Err("tank dimensions must be above 0");
+        }
+        // The bands are found by comparing against each mark in turn
+        let marks = [
+            0.0,
+            self.level_low_mm,
+            self.level_normal_mm,
+            self.level_high_mm,
+            self.level_overflow_mm,
+        ];
+        if !marks.windows(2).all(|pair| pair[0] < pair[1]) {
+            return Err("level marks must go up from low to overflow, all above 0");
+        }
+        if self.level_analog_empty_counts == self.level_analog_full_counts {
+            return Err("level_analog_empty_counts and level_analog_full_counts must differ");
+        }
+        if !non_negative(self.level_sensor_mount_mm) {
+            return Err("level_sensor_mount_mm can't be negative");
+        }
+        Ok(())
+    }
+
+    pub fn current_thresholds(&self) -> CurrentThresholds {
+        CurrentThresholds {
+            ma_per_count: self.current_ma_per_count,
+            over_current_ma: self.over_current_ma,
+            no_load_ma: self.no_load_ma,
+        }
+    }
+
+    fn encode(&self, out: &mut EntryWriter) -> Result<(), ConfigError> {
+        out.put(ConfigKey::IpAddress, &self.ip_address)?;
+        out.put(ConfigKey::PrefixLen, &[self.prefix_len])?;
+        out.put(ConfigKey::Gateway, &self.gateway)?;
+        out.put(ConfigKey::PhUpper, &self.ph_upper.to_le_bytes())?;
+        out.put(ConfigKey::PhLower, &self.ph_lower.to_le_bytes())?;
+        out.put(ConfigKey::EcUpper, &self.ec_upper.to_le_bytes())?;
+        out.put(ConfigKey::EcLower, &self.ec_lower.to_le_bytes())?;
+        out.put(ConfigKey::EcAddress, &[self.ec_address])?;
+        out.put(ConfigKey::PhAddress, &[self.ph_address])?;
+        out.put(ConfigKey::EcInterval, &self.ec_interval_secs.to_le_bytes())?;
+        out.put(ConfigKey::PhInterval, &self.ph_interval_secs.to_le_bytes())?;
+        out.put(
+            ConfigKey::WaterLevelInterval,
+            &self.water_level_interval_secs.to_le_bytes(),
+        )?;
+        out.put(ConfigKey::PhTarget, &self.ph_target.to_le_bytes())?;
+        out.put(ConfigKey::PhMlPerUnit, &self.ph_ml_per_unit.to_le_bytes())?;
+        out.put(ConfigKey::PhMaxDose, &self.ph_max_dose_ml.to_le_bytes())?;
+        out.put(ConfigKey::PhMaxDaily, &self.ph_max_daily_ml.to_le_bytes())?;
+        out.put(ConfigKey::PhMixing, &self.ph_mixing_secs.to_le_bytes())?;
+        out.put(ConfigKey::EcTarget, &self.ec_target.to_le_bytes())?;
+        out.put(
+            ConfigKey::ReservoirLitres,
+            &self.reservoir_litres.to_le_bytes(),
+        )?;
+        out.put(
+            ConfigKey::NutrientStrength,
+            &self.nutrient_ec_per_ml_per_l.to_le_bytes(),
+        )?;
+        out.put(
+            ConfigKey::NutrientRatioA,
+            &self.nutrient_ratio_a.to_le_bytes(),
+        )?;
+        out.put(
+            ConfigKey::NutrientRatioB,
+            &self.nutrient_ratio_b.to_le_bytes(),
+        )?;
+        out.put(
+            ConfigKey::NutrientRatioC,
+            &self.nutrient_ratio_c.to_le_bytes(),
+        )?;
+        out.put(ConfigKey::EcMaxDose, &self.ec_max_dose_ml.to_le_bytes())?;
+        out.put(ConfigKey::EcMaxDaily, &self.ec_max_daily_ml.to_le_bytes())?;
+        out.put(
+            ConfigKey::EcPartMixing,
+            &self.ec_part_mixing_secs.to_le_bytes(),
+        )?;
+        out.put(ConfigKey::EcMixing, &self.ec_mixing_secs.to_le_bytes())?;
+        out.put(ConfigKey::DoseSettle, &self.dose_settle_secs.to_le_bytes())?;
+        out.put(
+            ConfigKey::SensorMaxAge,
+            &self.sensor_max_age_secs.to_le_bytes(),
+        )?;
+        out.put(ConfigKey::PumpMaxRun, &self.pump_max_run_secs.to_le_bytes())?;
+        out.put(
+            ConfigKey::PumpMaxDaily,
+            &self.pump_max_daily_ml.to_le_bytes(),
+        )?;
+        out.put(
+            ConfigKey::PumpMinInterval,
+            &self.pump_min_interval_secs.to_le_bytes(),
+        )?;
+        let pump_keys = [
+            ConfigKey::PhUpPump,
+            ConfigKey::PhDownPump,
+            ConfigKey::PartAPump,
+            ConfigKey::PartBPump,
+            ConfigKey::PartCPump,
+        ];
+        for (key, calibration) in pump_keys.into_iter().zip(&self.pump_calibration) {
+            let mut value = [0; 5];
+            value[..4].copy_from_slice(&calibration.ml_per_sec.to_le_bytes());
+            value[4] = calibration.duty_percent;
+            out.put(key, &value)?;
+        }
+        let dispensed_keys = [
+            ConfigKey::PhUpDispensed,
+            ConfigKey::PhDownDispensed,
+            ConfigKey::PartADispensed,
+            ConfigKey::PartBDispensed,
+            ConfigKey::PartCDispensed,
+        ];
+        for (key, ml) in dispensed_keys.into_iter().zip(&self.pump_dispensed_ml) {
+            out.put(key, &ml.to_le_bytes())?;
+        }
+        out.put(
+            ConfigKey::CurrentScale,
+            &self.current_ma_per_count.to_le_bytes(),
+        )?;
+        out.put(ConfigKey::OverCurrent, &self.over_current_ma.to_le_bytes())?;
+        out.put(ConfigKey::NoLoadCurrent, &self.no_load_ma.to_le_bytes())?;
+        out.put(ConfigKey::LightOn, &self.light_on_mins.to_le_bytes())?;
+        out.put(
+            ConfigKey::LightPhotoperiod,
+            &self.light_photoperiod_mins.to_le_bytes(),
+        )?;
+        out.put(ConfigKey::LightRamp, &self.light_ramp_mins.to_le_bytes())?;
+        out.put(ConfigKey::LightMaxPercent, &[self.light_max_percent])?;
+        out.put(ConfigKey::LightWasOn, &[self.light_was_on as u8])?;
+        out.put(ConfigKey::UtcOffset, &self.utc_offset_mins.to_le_bytes())?;
+        out.put(ConfigKey::NtpServer, &self.ntp_server)?;
+        out.put(
+            ConfigKey::IrrigationDayOn,
+            &self.irrigation_day_on_mins.to_le_bytes(),
+        )?;
+        out.put(
+            ConfigKey::IrrigationDayPeriod,
+            &self.irrigation_day_period_mins.to_le_bytes(),
+        )?;
+        out.put(
+            ConfigKey::IrrigationNightOn,
+            &self.irrigation_night_on_mins.to_le_bytes(),
+        )?;
+        out.put(
+            ConfigKey::IrrigationNightPeriod,
+            &self.irrigation_night_period_mins.to_le_bytes(),
+        )?;
+        out.put(
+            ConfigKey::IrrigationMaxOn,
+            &self.irrigation_max_on_mins.to_le_bytes(),
+        )?;
+        out.put(
+            ConfigKey::TopUpMaxFill,
+            &self.top_up_max_fill_secs.to_le_bytes(),
+        )?;
+        out.put(
+            ConfigKey::TopUpFlowRate,
+            &self.top_up_litres_per_min.to_le_bytes(),
+        )?;
+        out.put(
+            ConfigKey::TopUpMixing,
+            &self.top_up_mixing_secs.to_le_bytes(),
+        )?;
+        out.put(ConfigKey::TankShape, &[self.tank_shape])?;
+        out.put(ConfigKey::TankLength, &self.tank_length_mm.to_le_bytes())?;
+        out.put(ConfigKey::TankWidth, &self.tank_width_mm.to_le_bytes())?;
+        out.put(ConfigKey::TankHeight, &self.tank_height_mm.to_le_bytes())?;
+        out.put(ConfigKey::LevelLow, &self.level_low_mm.to_le_bytes())?;
+        out.put(ConfigKey::LevelNormal, &self.level_normal_mm.to_le_bytes())?;
+        out.put(ConfigKey::LevelHigh, &self.level_high_mm.to_le_bytes())?;
+        out.put(
+            ConfigKey::LevelOverflow,
+            &self.level_overflow_mm.to_le_bytes(),
+        )?;
+        out.put(
+            ConfigKey::LevelAnalogEmpty,
+            &self.level_analog_empty_counts.to_le_bytes(),
+        )?;
+        out.put(
+            ConfigKey::LevelAnalogFull,
+            &self.level_analog_full_counts.to_le_bytes(),
+        )?;
+        out.put(
+            ConfigKey::LevelSensorMount,
+            &self.level_sensor_mount_mm.to_le_bytes(),
+        )?;
+        out.put(
+            ConfigKey::FloatDebounce,
+            &self.float_debounce_ms.to_le_bytes(),
+        )?;
+        if let Some(Emergency::Leak(probe)) = self.emergency {
+            out.put(ConfigKey::EmergencyLeak, &[probe])?;
+        }
+        Ok(())
+    }
+
+    // Unknown keys and values of the wrong size are skipped, leaving the default in place
+    fn apply(&mut self, key: u16, value: &[u8]) {
+        match ConfigKey::from_u16(key) {
+            Some(ConfigKey::IpAddress) => set_bytes(&mut self.ip_address, value),
+            Some(ConfigKey::PrefixLen) => set_u8(&mut self.prefix_len, value),
+            Some(ConfigKey::Gateway) => set_bytes(&mut self.gateway, value),
+            Some(ConfigKey::PhUpper) => set_f32(&mut self.ph_upper, value),
+            Some(ConfigKey::PhLower) => set_f32(&mut self.ph_lower, value),
+            Some(ConfigKey::EcUpper) => set_f32(&mut self.ec_upper, value),
+            Some(ConfigKey::EcLower) => set_f32(&mut self.ec_lower, value),
+            Some(ConfigKey::EcAddress) => set_u8(&mut self.ec_address, value),
+            Some(ConfigKey::PhAddress) => set_u8(&mut self.ph_address, value),
+            Some(ConfigKey::EcInterval) => set_u32(&mut self.ec_interval_secs, value),
+            Some(ConfigKey::PhInterval) => set_u32(&mut self.ph_interval_secs, value),
+            Some(ConfigKey::WaterLevelInterval) => {
+                set_u32(&mut self.water_level_interval_secs, value)
+            }
+            Some(ConfigKey::PhTarget) => set_f32(&mut self.ph_target, value),
+            Some(ConfigKey::PhMlPerUnit) => set_f32(&mut self.ph_ml_per_unit, value),
+            Some(ConfigKey::PhMaxDose) => set_f32(&mut self.ph_max_dose_ml, value),
+            Some(ConfigKey::PhMaxDaily) => set_f32(&mut self.ph_max_daily_ml, value),
+            Some(ConfigKey::PhMixing) => set_u32(&mut self.ph_mixing_secs, value),
+            Some(ConfigKey::EcTarget) => set_f32(&mut self.ec_target, value),
+            Some(ConfigKey::ReservoirLitres) => set_f32(&mut self.reservoir_litres, value),
+            Some(ConfigKey::NutrientStrength) => set_f32(&mut self.nutrient_ec_per_ml_per_l, value),
+            Some(ConfigKey::NutrientRatioA) => set_f32(&mut self.nutrient_ratio_a, value),
+            Some(ConfigKey::NutrientRatioB) => set_f32(&mut self.nutrient_ratio_b, value),
+            Some(ConfigKey::NutrientRatioC) => set_f32(&mut self.nutrient_ratio_c, value),
+            Some(ConfigKey::EcMaxDose) => set_f32(&mut self.ec_max_dose_ml, value),
+            Some(ConfigKey::EcMaxDaily) => set_f32(&mut self.ec_max_daily_ml, value),
+            Some(ConfigKey::EcPartMixing) => set_u32(&mut self.ec_part_mixing_secs, value),
+            Some(ConfigKey::EcMixing) => set_u32(&mut self.ec_mixing_secs, value),
+            Some(ConfigKey::DoseSettle) => set_u32(&mut self.dose_settle_secs, value),
+            Some(ConfigKey::SensorMaxAge) => set_u32(&mut self.sensor_max_age_secs, value),
+            Some(ConfigKey::PumpMaxRun) => set_u32(&mut self.pump_max_run_secs, value),
+            Some(ConfigKey::PumpMaxDaily) => set_f32(&mut self.pump_max_daily_ml, value),
+            Some(ConfigKey::PumpMinInterval) => set_u32(&mut self.pump_min_interval_secs, value),
+            // Only in v1 configs, which `migrate` takes care of
+            Some(ConfigKey::PhPumpFlow | ConfigKey::NutrientPumpFlow) => {}
+            Some(ConfigKey::PhUpPump) => {
+                set_calibration(&mut self.pump_calibration[PH_UP_PUMP], value)
+            }
+            Some(ConfigKey::PhDownPump) => {
+                set_calibration(&mut self.pump_calibration[PH_DOWN_PUMP], value)
+            }
+            Some(ConfigKey::PartAPump) => {
+                set_calibration(&mut self.pump_calibration[PART_A_PUMP], value)
+            }
+            Some(ConfigKey::PartBPump) => {
+                set_calibration(&mut self.pump_calibration[PART_B_PUMP], value)
+            }
+            Some(ConfigKey::PartCPump) => {
+                set_calibration(&mut self.pump_calibration[PART_C_PUMP], value)
+            }
+            Some(ConfigKey::PhUpDispensed) => {
+                set_f32(&mut self.pump_dispensed_ml[PH_UP_PUMP], value)
+            }
+            Some(ConfigKey::PhDownDispensed) => {
+                set_f32(&mut self.pump_dispensed_ml[PH_DOWN_PUMP], value)
+            }
+            Some(ConfigKey::PartADispensed) => {
+                set_f32(&mut self.pump_dispensed_ml[PART_A_PUMP], value)
+            }
+            Some(ConfigKey::PartBDispensed) => {
+                set_f32(&mut self.pump_dispensed_ml[PART_B_PUMP], value)
+            }
+            Some(ConfigKey::PartCDispensed) => {
+                set_f32(&mut self.pump_dispensed_ml[PART_C_PUMP], value)
+            }
+            Some(ConfigKey::CurrentScale) => set_f32(&mut self.current_ma_per_count, value),
+            Some(ConfigKey::OverCurrent) => set_u32(&mut self.over_current_ma, value),
+            Some(ConfigKey::NoLoadCurrent) => set_u32(&mut self.no_load_ma, value),
+            Some(ConfigKey::LightOn) => set_u32(&mut self.light_on_mins, value),
+            Some(ConfigKey::LightPhotoperiod) => set_u32(&mut self.light_photoperiod_mins, value),
+            Some(ConfigKey::LightRamp) => set_u32(&mut self.light_ramp_mins, value),
+            Some(ConfigKey::LightMaxPercent) => set_u8(&mut self.light_max_percent, value),
+            Some(ConfigKey::LightWasOn) => {
+                if let [on] = value {
+                    self.light_was_on = *on != 0;
+                }
+            }
+            Some(ConfigKey::UtcOffset) => set_i32(&mut self.utc_offset_mins, value),
+            Some(ConfigKey::NtpServer) => set_bytes(&mut self.ntp_server, value),
+            Some(ConfigKey::IrrigationDayOn) => set_u32(&mut self.irrigation_day_on_mins, value),
+            Some(ConfigKey::IrrigationDayPeriod) => {
+                set_u32(&mut self.irrigation_day_period_mins, value)
+            }
+            Some(ConfigKey::IrrigationNightOn) => {
+                set_u32(&mut self.irrigation_night_on_mins, value)
+            }
+            Some(ConfigKey::IrrigationNightPeriod) => {
+                set_u32(&mut self.irrigation_night_period_mins, value)
+            }
+            Some(ConfigKey::IrrigationMaxOn) => set_u32(&mut self.irrigation_max_on_mins, value),
+            Some(ConfigKey::TopUpMaxFill) => set_u32(&mut self.top_up_max_fill_secs, value),
+            Some(ConfigKey::TopUpFlowRate) => set_f32(&mut self.top_up_litres_per_min, value),
+            Some(ConfigKey::TopUpMixing) => set_u32(&mut self.top_up_mixing_secs, value),
+            Some(ConfigKey::TankShape) => set_u8(&mut self.tank_shape, value),
+            Some(ConfigKey::TankLength) => set_f32(&mut self.tank_length_mm, value),
+            Some(ConfigKey::TankWidth) => set_f32(&mut self.tank_width_mm, value),
+            Some(ConfigKey::TankHeight) => set_f32(&mut self.tank_height_mm, value),
+            Some(ConfigKey::LevelLow) => set_f32(&mut self.level_low_mm, value),
+            Some(ConfigKey::LevelNormal) => set_f32(&mut self.level_normal_mm, value),
+            Some(ConfigKey::LevelHigh) => set_f32(&mut self.level_high_mm, value),
+            Some(ConfigKey::LevelOverflow) => set_f32(&mut self.level_overflow_mm, value),
+            Some(ConfigKey::LevelAnalogEmpty) => {
+                set_u32(&mut self.level_analog_empty_counts, value)
+            }
+            Some(ConfigKey::LevelAnalogFull) => set_u32(&mut self.level_analog_full_counts, value),
+            Some(ConfigKey::LevelSensorMount) => set_f32(&mut self.level_sensor_mount_mm, value),
+            Some(ConfigKey::FloatDebounce) => set_u32(&mut self.float_debounce_ms, value),
+            Some(ConfigKey::EmergencyLeak) => {
+                if let [probe] = value {
+                    self.emergency = Some(Emergency::Leak(*probe));
+                }
+            }
+            None => warn!("Ignoring unknown config key {}", key),
+        }
+    }
+}
+
+// Written this way round so NaN fails too
+fn non_negative(value: f32) -> bool {
+    value >= 0.0
+}
+
+fn positive(value: f32) -> bool {
+    value > 0.0
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config::defaults()
+    }
+}
+
+// Key ids are stored in flash, so never reuse or renumber them
+#[derive(Debug, Clone, Copy)]
+#[repr(u16)]
+enum ConfigKey {
+    IpAddress = 1,
+    PrefixLen = 2,
+    Gateway = 3,
+    PhUpper = 10,
+    PhLower = 11,
+    EcUpper = 12,
+    EcLower = 13,
+    EcAddress = 20,
+    PhAddress = 21,
+    EcInterval = 30,
+    PhInterval = 31,
+    WaterLevelInterval = 32,
+    PhTarget = 40,
+    PhMlPerUnit = 41,
+    PhMaxDose = 42,
+    PhMaxDaily = 43,
+    PhMixing = 44,
+    // v1 only, replaced by the per-pump calibrations
+    PhPumpFlow = 45,
+    EcTarget = 50,
+    ReservoirLitres = 51,
+    NutrientStrength = 52,
+    NutrientRatioA = 53,
+    NutrientRatioB = 54,
+    NutrientRatioC = 55,
+    EcMaxDose = 56,
+    EcMaxDaily = 57,
+    EcPartMixing = 58,
+    EcMixing = 59,
+    // v1 only, replaced by the per-pump calibrations
+    NutrientPumpFlow = 60,
+    DoseSettle = 70,
+    SensorMaxAge = 71,
+    PumpMaxRun = 80,
+    PumpMaxDaily = 81,
+    PumpMinInterval = 82,
+    PhUpPump = 90,
+    PhDownPump = 91,
+    PartAPump = 92,
+    PartBPump = 93,
+    PartCPump = 94,
+    PhUpDispensed = 95,
+    PhDownDispensed = 96,
+    PartADispensed = 97,
+    PartBDispensed = 98,
+    PartCDispensed = 99,
+    CurrentScale = 100,
+    OverCurrent = 101,
+    NoLoadCurrent = 102,
+    LightOn = 110,
+    LightPhotoperiod = 111,
+    LightRamp = 112,
+    LightMaxPercent = 113,
+    UtcOffset = 114,
+    NtpServer = 115,
+    LightWasOn = 116,
+    IrrigationDayOn = 120,
+    IrrigationDayPeriod = 121,
+    IrrigationNightOn = 122,
+    IrrigationNightPeriod = 123,
+    IrrigationMaxOn = 124,
+    TopUpMaxFill = 130,
+    TopUpFlowRate = 131,
+    TopUpMixing = 132,
+    TankShape = 140,
+    TankLength = 141,
+    TankWidth = 142,
+    TankHeight = 143,
+    LevelLow = 144,
+    LevelNormal = 145,
+    LevelHigh = 146,
+    LevelOverflow = 147,
+    LevelAnalogEmpty = 148,
+    LevelAnalogFull = 149,
+    LevelSensorMount = 150,
+    FloatDebounce = 151,
+    // Only written while latched
+    EmergencyLeak = 160,
+}
+
+impl ConfigKey {
+    fn from_u16(key: u16) -> Option<ConfigKey> {
+        match key {
+            1 => Some(Self::IpAddress),
+            2 => Some(Self::PrefixLen),
+            3 => Some(Self::Gateway),
+            10 => Some(Self::PhUpper),
+            11 => Some(Self::PhLower),
+            12 => Some(Self::EcUpper),
+            13 => Some(Self::EcLower),
+            20 => Some(Self::EcAddress),
+            21 => Some(Self::PhAddress),
+            30 => Some(Self::EcInterval),
+            31 => Some(Self::PhInterval),
+            32 => Some(Self::WaterLevelInterval),
+            40 => Some(Self::PhTarget),
+            41 => Some(Self::PhMlPerUnit),
+            42 => Some(Self::PhMaxDose),
+            43 => Some(Self::PhMaxDaily),
+            44 => Some(Self::PhMixing),
+            45 => Some(Self::PhPumpFlow),
+            50 => Some(Self::EcTarget),
+            51 => Some(Self::ReservoirLitres),
+            52 => Some(Self::NutrientStrength),
+            53 => Some(Self::NutrientRatioA),
+            54 => Some(Self::NutrientRatioB),
+            55 => Some(Self::NutrientRatioC),
+            56 => Some(Self::EcMaxDose),
+            57 => Some(Self::EcMaxDaily),
+            58 => Some(Self::EcPartMixing),
+            59 => Some(Self::EcMixing),
+            60 => Some(Self::NutrientPumpFlow),
+            70 => Some(Self::DoseSettle),
+            71 => Some(Self::SensorMaxAge),
+            80 => Some(Self::PumpMaxRun),
+            81 => Some(Self::PumpMaxDaily),
+            82 => Some(Self::PumpMinInterval),
+            90 => Some(Self::PhUpPump),
+            91 => Some(Self::PhDownPump),
+            92 => Some(Self::PartAPump),
+            93 => Some(Self::PartBPump),
+            94 => Some(Self::PartCPump),
+            95 => Some(Self::PhUpDispensed),
+            96 => Some(Self::PhDownDispensed),
+            97 => Some(Self::PartADispensed),
+            98 => Some(Self::PartBDispensed),
+            99 => Some(Self::PartCDispensed),
+            100 => Some(Self::CurrentScale),
+            101 => Some(Self::OverCurrent),
+            102 => Some(Self::NoLoadCurrent),
+            110 => Some(Self::LightOn),
+            111 => Some(Self::LightPhotoperiod),
+            112 => Some(Self::LightRamp),
+            113 => Some(Self::LightMaxPercent),
+            114 => Some(Self::UtcOffset),
+            115 => Some(Self::NtpServer),
+            116 => Some(Self::LightWasOn),
+            120 => Some(Self::IrrigationDayOn),
+            121 => Some(Self::IrrigationDayPeriod),
+            122 => Some(Self::IrrigationNightOn),
+            123 => Some(Self::IrrigationNightPeriod),
+            124 => Some(Self::IrrigationMaxOn),
+            130 => Some(Self::TopUpMaxFill),
+            131 => Some(Self::TopUpFlowRate),
+            132 => Some(Self::TopUpMixing),
+            140 => Some(Self::TankShape),
+            141 => Some(Self::TankLength),
+            142 => Some(Self::TankWidth),
+            143 => Some(Self::TankHeight),
+            144 => Some(Self::LevelLow),
+            145 => Some(Self::LevelNormal),
+            146 => Some(Self::LevelHigh),
+            147 => Some(Self::LevelOverflow),
+            148 => Some(Self::LevelAnalogEmpty),
+            149 => Some(Self::LevelAnalogFull),
+            150 => Some(Self::LevelSensorMount),
+            151 => Some(Self::FloatDebounce),
+            160 => Some(Self::EmergencyLeak),
+            _ => None,
+        }
+    }
+}
+
+fn set_bytes<const N: usize>(field: &mut [u8; N], value: &[u8]) {
+    if let Ok(v) = value.try_into() {
+        *field = v;
+    }
+}
+
+fn set_u8(field: &mut u8, value: &[u8]) {
+    if let [v] = value {
+        *field = *v;
+    }
+}
+
+fn set_u32(field: &mut u32, value: &[u8]) {
+    if let Ok(v) = value.try_into() {
+        *field = u32::from_le_bytes(v);
+    }
+}
+
+fn set_i32(field: &mut i32, value: &[u8]) {
+    if let Ok(v) = value.try_into() {
+        *field = i32::from_le_bytes(v);
+    }
+}
+
+fn set_f32(field: &mut f32, value: &[u8]) {
+    if let Ok(v) = value.try_into() {
+        *field = f32::from_le_bytes(v);
+    }
+}
+
+fn set_calibration(field: &mut PumpCalibration, value: &[u8]) {
+    if let [a, b, c, d, duty] = value {
+        field.ml_per_sec = f32::from_le_bytes([*a, *b, *c, *d]);
+        field.duty_percent = *duty;
+    }
+}
+
+// Upgrades a config written by older firmware one version at a time, given its entries.
+// Saving the migrated config afterwards drops any keys that are no longer used.
+fn migrate(from: u16, config: &mut Config, entries: &[u8]) {
+    for version in from..CONFIG_VERSION {
+        // Add a step here when bumping CONFIG_VERSION
+        if version == 1 {
+            migrate_v1(config, entries);
+        }
+    }
+}
+
+// v1 had one flow rate shared by both pH pumps and one shared by the nutrient pumps
+fn migrate_v1(config: &mut Config, entries: &[u8]) {
+    for (key, value) in Entries(entries) {
+        let pumps: &[usize] = match ConfigKey::from_u16(key) {
+            Some(ConfigKey::PhPumpFlow) => &[PH_UP_PUMP, PH_DOWN_PUMP],
+            Some(ConfigKey::NutrientPumpFlow) => &[PART_A_PUMP, PART_B_PUMP, PART_C_PUMP],
+            _ => continue,
+        };
+        for pump in pumps {
+            set_f32(&mut config.pump_calibration[*pump].ml_per_sec, value);
+        }
+    }
+}
+
+// Reads back what `EntryWriter` wrote, as (key, value)
+struct Entries<'a>(&'a [u8]);
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = (u16, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let [k0, k1, n, rest @ ..] = self.0 else {
+            return None;
+        };
+        let n = *n as usize;
+        if rest.len() < n {
+            warn!("Truncated config entry");
+            return None;
+        }
+        let (value, rest) = rest.split_at(n);
+        self.0 = rest;
+        Some((u16::from_le_bytes([*k0, *k1]), value))
+    }
+}
+
+// Writes entries as key (u16 LE), length (u8), value
+struct EntryWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl EntryWriter<'_> {
+    fn put(&mut self, key: ConfigKey, value: &[u8]) -> Result<(), ConfigError> {
+        let end = self.pos + 3 + value.len();
+        if end > self.buf.len() {
+            return Err(ConfigError::TooLarge);
+        }
+        self.buf[self.pos..self.pos + 2].copy_from_slice(&(key as u16).to_le_bytes());
+        self.buf[self.pos + 2] = value.len() as u8;
+        self.buf[self.pos + 3..end].copy_from_slice(value);
+        self.pos = end;
+        Ok(())
+    }
+}
+
+pub struct ConfigStore {
+    flash: ConfigFlash,
+    // Sequence number of the newest valid record, 0 if there is none
+    seq: u32,
+    // Sector (0 or 1) holding the newest valid record
+    active: u32,
+}
+
+impl ConfigStore {
+    pub fn new(flash: ConfigFlash) -> Self {
+        ConfigStore {
+            flash,
+            seq: 0,
+            active: 1,
+        }
+    }
+
+    /// Loads the newest valid config, falling back to the defaults if there isn't one or it
+    /// doesn't pass validation. Configs from older firmware are migrated and written back.
+    pub fn load(&mut self) -> Config {
+        let mut newest: Option<(u32, u32, u16, [u8; RECORD_LEN])> = None;
+        for sector in 0..2 {
+            match self.read_record(sector) {
+                Ok((seq, version, record)) => {
+                    if newest.is_none_or(|(s, ..)| seq > s) {
+                        newest = Some((sector, seq, version, record));
+                    }
+                }
+                Err(e) => info!("Config sector {} not usable: {}", sector, e),
+            }
+        }
+
+        let Some((sector, seq, version, record)) = newest else {
+            info!("No stored config, using defaults");
+            return Config::defaults();
+        };
+        self.active = sector;
+        self.seq = seq;
+
+        let mut config = Config::defaults();
+        let len = u16::from_le_bytes([record[6], record[7]]) as usize;
+        let entries = &record[HEADER_LEN..HEADER_LEN + len];
+        for (key, value) in Entries(entries) {
+            config.apply(key, value);
+        }
+        if version < CONFIG_VERSION {
+            info!("Migrating config from v{} to v{}", version, CONFIG_VERSION);
+            migrate(version, &mut config, entries);
+        }
+
+        // Settings that don't make sense could have anything running wild
+        if let Err(e) = config.validate() {
+            error!("Stored config is invalid ({}), using defaults", e);
+            // Bad settings are no reason to forget a leak
+            return Config {
+                emergency: config.emergency,
+                ..Config::defaults()
+            };
+        }
+        if version < CONFIG_VERSION
+            && let Err(e) = self.save(&config)
+        {
+            error!("Failed to save migrated config: {}", e);
+        }
+        config
+    }
+
+    /// Writes the config to the sector not holding the current copy
+    pub fn save(&mut self, config: &Config) -> Result<(), ConfigError> {
+        let mut record = [0xFF; RECORD_LEN];
+        let mut writer = EntryWriter {
+            buf: &mut record[HEADER_LEN..],
+            pos: 0,
+        };
+        config.encode(&mut writer)?;
+        let len = writer.pos as u16;
+        let seq = self.seq.wrapping_add(1);
+
+        record[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        record[4..6].copy_from_slice(&CONFIG_VERSION.to_le_bytes());
+        record[6..8].copy_from_slice(&len.to_le_bytes());
+        record[8..12].copy_from_slice(&seq.to_le_bytes());
+        let crc = record_crc(&record, len as usize);
+        record[12..16].copy_from_slice(&crc.to_le_bytes());
+
+        let sector = 1 - self.active;
+        let offset = sector_offset(sector);
+        self.flash
+            .blocking_erase(offset, offset + ERASE_SIZE as u32)
+            .map_err(|_| ConfigError::Flash)?;
+        self.flash
+            .blocking_write(offset, &record)
+            .map_err(|_| ConfigError::Flash)?;
+
+        self.active = sector;
+        self.seq = seq;
+        info!("Saved config (seq {})", seq);
+        Ok(())
+    }
+
+    /// Erases both config sectors, so the next load returns the defaults
+    pub fn factory_reset(&mut self) -> Result<(), ConfigError> {
+        warn!("Factory resetting config");
+        self.flash
+            .blocking_erase(CONFIG_OFFSET, CONFIG_OFFSET + 2 * ERASE_SIZE as u32)
+            .map_err(|_| ConfigError::Flash)?;
+        self.seq = 0;
+        self.active = 1;
+        Ok(())
+    }
+
+    fn read_record(&mut self, sector: u32) -> Result<(u32, u16, [u8; RECORD_LEN]), ConfigError> {
+        let mut record = [0; RECORD_LEN];
+        self.flash
+            .blocking_read(sector_offset(sector), &mut record)
+            .map_err(|_| ConfigError::Flash)?;
+
+        if u32::from_le_bytes([record[0], record[1], record[2], record[3]]) != MAGIC {
+            return Err(ConfigError::Empty);
+        }
+        let version = u16::from_le_bytes([record[4], record[5]]);
+        let len = u16::from_le_bytes([record[6], record[7]]) as usize;
+        if len > RECORD_LEN - HEADER_LEN {
+            return Err(ConfigError::Corrupt);
+        }
+        let seq = u32::from_le_bytes([record[8], record[9], record[10], record[11]]);
+        let crc = u32::from_le_bytes([record[12], record[13], record[14], record[15]]);
+        if crc != record_crc(&record, len) {
+            return Err(ConfigError::Corrupt);
+        }
+        Ok((seq, version, record))
+    }
+}
+
+// Covers the header (minus the magic and the crc itself) and the entries
+fn record_crc(record: &[u8; RECORD_LEN], len: usize) -> u32 {
+    let mut digest = CRC.digest();
+    digest.update(&record[4..12]);
+    digest.update(&record[HEADER_LEN..HEADER_LEN + len]);
+    digest.finalize()
+}
+
+fn sector_offset(sector: u32) -> u32 {
+    CONFIG_OFFSET + sector * ERASE_SIZE as u32
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Flash error")]
+    Flash,
+    #[error("Sector is empty")]
+    Empty,
+    #[error("Record is corrupt")]
+    Corrupt,
+    #[error("Config does not fit in a record")]
+    TooLarge,
+    #[error("Invalid config: {0}")]
+    Invalid(&'static str),
+}
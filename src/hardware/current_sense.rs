@@ -0,0 +1,56 @@
+use embassy_rp::adc::{Adc, Async, Channel};
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+
+use super::actuator::MotorFault;
+
+pub type SharedAdc = Mutex<NoopRawMutex, Adc<'static, Async>>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CurrentThresholds {
+    /// Scale from raw ADC counts to mA, depends on the sense resistor and amplifier
+    pub ma_per_count: f32,
+    /// Anything above this means the motor is stalled or the tube is blocked
+    pub over_current_ma: u32,
+    /// Anything below this means the pump is running dry or is disconnected
+    pub no_load_ma: u32,
+}
+
+/// Motor current measured on one of the RP2040's ADC pins
+pub struct CurrentSense<'a> {
+    adc: &'a SharedAdc,
+    channel: Channel<'a>,
+    thresholds: CurrentThresholds,
+}
+
+impl<'a> CurrentSense<'a> {
+    pub fn new(adc: &'a SharedAdc, channel: Channel<'a>, thresholds: CurrentThresholds) -> Self {
+        CurrentSense {
+            adc,
+            channel,
+            thresholds,
+        }
+    }
+
+    pub fn set_thresholds(&mut self, thresholds: CurrentThresholds) {
+        self.thresholds = thresholds;
+    }
+
+    pub async fn read_ma(&mut self) -> Option<f32> {
+        let counts = self.adc.lock().await.read(&mut self.channel).await.ok()?;
+        Some(counts as f32 * self.thresholds.ma_per_count)
+    }
+
+    /// Takes a reading and classifies it, only meaningful while the motor is running
+    pub async fn check(&mut self) -> Option<MotorFault> {
+        let Some(ma) = self.read_ma().await else {
+            return Some(MotorFault::SenseFailed);
+        };
+        if ma > self.thresholds.over_current_ma as f32 {
+            Some(MotorFault::Stalled)
+        } else if ma < self.thresholds.no_load_ma as f32 {
+            Some(MotorFault::NoLoad)
+        } else {
+            None
+        }
+    }
+}
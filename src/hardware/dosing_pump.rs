@@ -0,0 +1,116 @@
+use embassy_time::{Duration, Instant};
+
+use super::actuator::{Actuator, ActuatorError, MotorFault};
+
+/// How much a pump moves at a given duty cycle
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PumpCalibration {
+    pub ml_per_sec: f32,
+    pub duty_percent: u8,
+}
+
+impl PumpCalibration {
+    pub const fn new(ml_per_sec: f32, duty_percent: u8) -> Self {
+        PumpCalibration {
+            ml_per_sec,
+            duty_percent,
+        }
+    }
+}
+
+/// A finished calibration run, waiting for the volume that came out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CalibrationRun {
+    pub secs: u32,
+    // The flow rate only holds at the duty it was measured at
+    pub duty_percent: u8,
+}
+
+/// A peristaltic pump that dispenses by volume, usually a `Motor` on an H-bridge
+pub struct DosingPump<A: Actuator> {
+    pump: A,
+    calibration: PumpCalibration,
+    // Total since the bottle was last refilled
+    dispensed_ml: f32,
+}
+
+impl<A: Actuator> DosingPump<A> {
+    pub fn new(pump: A, calibration: PumpCalibration) -> Self {
+        DosingPump {
+            pump,
+            calibration,
+            dispensed_ml: 0.0,
+        }
+    }
+
+    /// Carries on from a total saved before a reset
+    pub fn with_dispensed(mut self, ml: f32) -> Self {
+        self.dispensed_ml = ml;
+        self
+    }
+
+    pub fn set_calibration(&mut self, calibration: PumpCalibration) {
+        self.calibration = calibration;
+    }
+
+    /// How long the pump has to run to dispense `ml`
+    pub fn run_time_for(&self, ml: f32) -> Duration {
+        Duration::from_millis((ml / self.calibration.ml_per_sec * 1000.0) as u64)
+    }
+
+    /// Dispenses `ml` with a single timed run
+    pub async fn dispense(&mut self, ml: f32) -> Result<(), ActuatorError> {
+        self.run_for(self.run_time_for(ml)).await
+    }
+
+    /// Runs at the calibrated duty cycle for `duration`, then brakes.
+    /// Stops early if current sensing finds a fault.
+    pub async fn run_for(&mut self, duration: Duration) -> Result<(), ActuatorError> {
+        let start = Instant::now();
+        let result = self
+            .pump
+            .run_for(self.calibration.duty_percent, duration)
+            .await;
+
+        // Counts what actually ran, which is less than asked for after a fault
+        let ran = start.elapsed().min(duration);
+        self.dispensed_ml += self.calibration.ml_per_sec * ran.as_millis() as f32 / 1000.0;
+        result
+    }
+
+    /// First half of a calibration: run for `secs` into a measuring cylinder, at the same duty
+    /// cycle doses use
+    pub async fn calibration_run(&mut self, secs: u32) -> Result<CalibrationRun, ActuatorError> {
+        let duty_percent = self.calibration.duty_percent;
+        self.run_for(Duration::from_secs(secs as u64)).await?;
+        Ok(CalibrationRun { secs, duty_percent })
+    }
+
+    /// Second half of a calibration: `measured_ml` came out during `run`
+    pub fn calibrate(&mut self, run: CalibrationRun, measured_ml: f32) -> PumpCalibration {
+        self.calibration = PumpCalibration::new(measured_ml / run.secs as f32, run.duty_percent);
+        self.calibration
+    }
+
+    pub fn fault(&self) -> Option<MotorFault> {
+        self.pump.fault()
+    }
+
+    pub fn clear_fault(&mut self) {
+        self.pump.clear_fault();
+    }
+
+    pub fn actuator_mut(&mut self) -> &mut A {
+        &mut self.pump
+    }
+
+    /// Volume dispensed since the last refill
+    pub fn dispensed_ml(&self) -> f32 {
+        self.dispensed_ml
+    }
+
+    /// Call after refilling the bottle
+    pub fn reset_dispensed(&mut self) {
+        self.dispensed_ml = 0.0;
+    }
+}
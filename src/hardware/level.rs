@@ -0,0 +1,241 @@
+use embassy_futures::select::{Either, select, select4};
+use embassy_rp::{
+    adc::Channel,
+    gpio::{Input, Output},
+};
+use embassy_time::{Duration, Instant, Timer, with_timeout};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use super::current_sense::SharedAdc;
+use crate::config::Config;
+pub use hydroponic_automation_embassy::level_band::{FloatReadings, LevelBand};
+
+// Longest echo from a cheap ultrasonic sensor, about 5m
+const ECHO_TIMEOUT: Duration = Duration::from_millis(30);
+// Speed of sound at room temperature, halved for the round trip
+const MM_PER_ECHO_US: f32 = 0.343 / 2.0;
+// Constant sloshing would otherwise keep a switch from ever settling
+const MAX_SETTLE_PERIODS: u32 = 10;
+
+// The band a continuous reading falls in, using the level marks from the config
+fn band_from_height(height_mm: f32, config: &Config) -> LevelBand {
+    if height_mm >= config.level_overflow_mm {
+        LevelBand::Overflow
+    } else if height_mm >= config.level_high_mm {
+        LevelBand::High
+    } else if height_mm >= config.level_normal_mm {
+        LevelBand::Normal
+    } else if height_mm >= config.level_low_mm {
+        LevelBand::Low
+    } else {
+        LevelBand::BelowLow
+    }
+}
+
+/// Converts a water height into a volume
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TankGeometry {
+    Rectangular {
+        length_mm: f32,
+        width_mm: f32,
+        height_mm: f32,
+    },
+    Cylinder {
+        diameter_mm: f32,
+        height_mm: f32,
+    },
+}
+
+impl TankGeometry {
+    pub fn from_config(config: &Config) -> TankGeometry {
+        match config.tank_shape {
+            1 => TankGeometry::Cylinder {
+                diameter_mm: config.tank_width_mm,
+                height_mm: config.tank_height_mm,
+            },
+            _ => TankGeometry::Rectangular {
+                length_mm: config.tank_length_mm,
+                width_mm: config.tank_width_mm,
+                height_mm: config.tank_height_mm,
+            },
+        }
+    }
+
+    fn height_mm(&self) -> f32 {
+        match *self {
+            TankGeometry::Rectangular { height_mm, .. }
+            | TankGeometry::Cylinder { height_mm, .. } => height_mm,
+        }
+    }
+
+    fn area_mm2(&self) -> f32 {
+        match *self {
+            TankGeometry::Rectangular {
+                length_mm,
+                width_mm,
+                ..
+            } => length_mm * width_mm,
+            TankGeometry::Cylinder { diameter_mm, .. } => {
+                core::f32::consts::PI * diameter_mm * diameter_mm / 4.0
+            }
+        }
+    }
+
+    pub fn litres(&self, water_mm: f32) -> f32 {
+        self.area_mm2() * water_mm.clamp(0.0, self.height_mm()) / 1_000_000.0
+    }
+
+    pub fn percent(&self, water_mm: f32) -> f32 {
+        (water_mm / self.height_mm() * 100.0).clamp(0.0, 100.0)
+    }
+}
+
+/// Float switches at the level marks, any that aren't fitted are `None`.
+/// Each switch reads high when the water is at or above it.
+pub struct FloatSwitches {
+    pub low: Option<Input<'static>>,
+    pub normal: Option<Input<'static>>,
+    pub high: Option<Input<'static>>,
+    pub overflow: Option<Input<'static>>,
+}
+
+impl FloatSwitches {
+    /// The band from the highest wet switch, or `Unknown` if none are fitted
+    pub fn band(&self) -> LevelBand {
+        let wet = |switch: &Option<Input<'static>>| switch.as_ref().map(|s| s.is_high());
+        FloatReadings {
+            low: wet(&self.low),
+            normal: wet(&self.normal),
+            high: wet(&self.high),
+            overflow: wet(&self.overflow),
+        }
+        .band()
+    }
+
+    /// Waits until any of the switches changes state
+    pub async fn wait_for_edge(&mut self) {
+        async fn edge(switch: &mut Option<Input<'static>>) {
+            match switch {
+                Some(switch) => switch.wait_for_any_edge().await,
+                None => core::future::pending().await,
+            }
+        }
+        select4(
+            edge(&mut self.low),
+            edge(&mut self.normal),
+            edge(&mut self.high),
+            edge(&mut self.overflow),
+        )
+        .await;
+    }
+
+    /// Waits until no switch has changed for `debounce`
+    pub async fn settle(&mut self, debounce: Duration) {
+        for _ in 0..MAX_SETTLE_PERIODS {
+            if let Either::First(()) = select(Timer::after(debounce), self.wait_for_edge()).await {
+                return;
+            }
+        }
+        warn!("Float switches still changing, using the current level");
+    }
+}
+
+/// A resistive (eTape) or pressure sensor on the ADC, linear between the empty and full readings
+pub struct AnalogLevel {
+    adc: &'static SharedAdc,
+    channel: Channel<'static>,
+}
+
+impl AnalogLevel {
+    #[allow(dead_code)] // No spare ADC pin on the current board
+    pub fn new(adc: &'static SharedAdc, channel: Channel<'static>) -> Self {
+        AnalogLevel { adc, channel }
+    }
+
+    async fn read_height_mm(&mut self, config: &Config) -> Option<f32> {
+        let counts = self.adc.lock().await.read(&mut self.channel).await.ok()? as f32;
+        let empty = config.level_analog_empty_counts as f32;
+        let full = config.level_analog_full_counts as f32;
+        if full == empty {
+            return None;
+        }
+        Some((counts - empty) / (full - empty) * config.tank_height_mm)
+    }
+}
+
+/// An ultrasonic distance sensor (HC-SR04, JSN-SR04T) looking down at the water
+pub struct Ultrasonic {
+    trigger: Output<'static>,
+    echo: Input<'static>,
+}
+
+impl Ultrasonic {
+    #[allow(dead_code)] // No spare pins on the current board
+    pub fn new(trigger: Output<'static>, echo: Input<'static>) -> Self {
+        Ultrasonic { trigger, echo }
+    }
+
+    async fn read_height_mm(&mut self, config: &Config) -> Option<f32> {
+        self.trigger.set_high();
+        Timer::after_micros(10).await;
+        self.trigger.set_low();
+
+        with_timeout(ECHO_TIMEOUT, self.echo.wait_for_high())
+            .await
+            .ok()?;
+        let start = Instant::now();
+        with_timeout(ECHO_TIMEOUT, self.echo.wait_for_low())
+            .await
+            .ok()?;
+        let distance_mm = start.elapsed().as_micros() as f32 * MM_PER_ECHO_US;
+        Some(config.level_sensor_mount_mm - distance_mm)
+    }
+}
+
+/// A sensor that measures the actual water height rather than a band
+#[allow(dead_code)]
+pub enum ContinuousLevel {
+    Analog(AnalogLevel),
+    Ultrasonic(Ultrasonic),
+}
+
+impl ContinuousLevel {
+    pub async fn read_height_mm(&mut self, config: &Config) -> Option<f32> {
+        match self {
+            ContinuousLevel::Analog(sensor) => sensor.read_height_mm(config).await,
+            ContinuousLevel::Ultrasonic(sensor) => sensor.read_height_mm(config).await,
+        }
+    }
+}
+
+/// A combined reading from all the level sensors
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub struct WaterLevel {
+    pub band: LevelBand,
+    // Only known with a continuous sensor
+    pub percent: Option<f32>,
+    pub litres: Option<f32>,
+}
+
+impl WaterLevel {
+    pub const UNKNOWN: WaterLevel = WaterLevel {
+        band: LevelBand::Unknown,
+        percent: None,
+        litres: None,
+    };
+
+    /// Float switches are trusted for the band, the continuous sensor fills in the rest
+    pub fn from_readings(floats: LevelBand, height_mm: Option<f32>, config: &Config) -> WaterLevel {
+        let tank = TankGeometry::from_config(config);
+        let band = match (floats, height_mm) {
+            (LevelBand::Unknown, Some(height)) => band_from_height(height, config),
+            (band, _) => band,
+        };
+        WaterLevel {
+            band,
+            percent: height_mm.map(|h| tank.percent(h)),
+            litres: height_mm.map(|h| tank.litres(h)),
+        }
+    }
+}
@@ -1,2 +1,7 @@
+pub mod current_sense;
+pub mod dosing_pump;
 pub mod ezo;
+pub mod level;
 pub mod motor;
+
+pub use hydroponic_automation_embassy::actuator;
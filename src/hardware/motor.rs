@@ -1,47 +1,196 @@
-use embassy_rp::{
-    Peripheral,
-    gpio::Output,
-    pwm::{ChannelAPin, Config, Pwm, SetDutyCycle, Slice},
+use core::convert::Infallible;
+use embassy_rp::{gpio::Output, pwm::SetDutyCycle};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal::digital::OutputPin;
+use thiserror::Error;
+
+use super::{
+    actuator::{Actuator, ActuatorError, MotorFault},
+    current_sense::CurrentSense,
 };
 
-pub struct Motor<'a, PWM: SetDutyCycle> {
-    ina: Output<'a>,
-    inb: Output<'a>,
+// How often the duty cycle is updated while ramping
+const RAMP_TICK_MS: u64 = 20;
+// Start-up inrush would look like a stall, so current isn't checked this early
+const SPIN_UP: Duration = Duration::from_millis(300);
+const CURRENT_SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How quickly a motor changes speed and how long it rests before reversing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RampProfile {
+    /// Percent of full duty per second while speeding up, 0 for instant
+    pub accel_percent_per_sec: u16,
+    /// Percent of full duty per second while slowing down, 0 for instant
+    pub decel_percent_per_sec: u16,
+    /// Time spent braked between directions
+    pub reverse_dwell: Duration,
+}
+
+impl RampProfile {
+    /// No ramping, for pumps that need exact timed runs
+    pub const INSTANT: RampProfile = RampProfile {
+        accel_percent_per_sec: 0,
+        decel_percent_per_sec: 0,
+        reverse_dwell: Duration::from_millis(100),
+    };
+    /// Full speed in 5 seconds, for the circulation pump
+    #[allow(dead_code)] // The current board switches its pumps with relays
+    pub const GENTLE: RampProfile = RampProfile {
+        accel_percent_per_sec: 20,
+        decel_percent_per_sec: 50,
+        reverse_dwell: Duration::from_millis(1000),
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Brake,
+    Clockwise,
+    #[allow(dead_code)] // Only the peristaltic pumps are on H-bridges, and they only run forwards
+    CounterClockwise,
+}
+
+/// A motor on an H-bridge, driven by two direction pins and a PWM enable.
+/// The pins are generic so the motor can be driven by something other than RP2040 GPIO.
+pub struct Motor<'a, PWM: SetDutyCycle, PIN: OutputPin<Error = Infallible> = Output<'a>> {
+    ina: PIN,
+    inb: PIN,
     pwm: PWM,
+    ramp: RampProfile,
+    direction: Direction,
+    duty: u16,
+    current: Option<CurrentSense<'a>>,
+    fault: Option<MotorFault>,
 }
 
-impl<'a, PWM: SetDutyCycle> Motor<'a, PWM> {
-    pub fn new(ina: impl Into<Output<'a>>, inb: impl Into<Output<'a>>, pwm: PWM) -> Motor<'a, PWM> {
+impl<'a, PWM: SetDutyCycle, PIN: OutputPin<Error = Infallible>> Motor<'a, PWM, PIN> {
+    /// Starts without ramping, so dosing pumps run for exactly as long as they're asked to
+    pub fn new(ina: PIN, inb: PIN, pwm: PWM) -> Motor<'a, PWM, PIN> {
         Motor {
-            ina: ina.into(),
-            inb: inb.into(),
-            pwm: pwm,
+            ina,
+            inb,
+            pwm,
+            ramp: RampProfile::INSTANT,
+            direction: Direction::Brake,
+            duty: 0,
+            current: None,
+            fault: None,
         }
     }
+
+    #[allow(dead_code)] // The current board switches its pumps with relays
+    pub fn with_ramp(mut self, ramp: RampProfile) -> Self {
+        self.ramp = ramp;
+        self
+    }
+
+    pub fn with_current_sense(mut self, current: CurrentSense<'a>) -> Self {
+        self.current = Some(current);
+        self
+    }
 }
 
-impl<'a, PWM: SetDutyCycle> Motor<'a, PWM> {
-    pub fn clockwise(&mut self) {
-        self.ina.set_high();
-        self.inb.set_low();
+impl<'a, PWM: SetDutyCycle, PIN: OutputPin<Error = Infallible>> Motor<'a, PWM, PIN> {
+    /// Reversing always ramps down and brakes for the dwell time first, leaving the motor stopped
+    pub async fn clockwise(&mut self) -> Result<(), MotorError<PWM::Error>> {
+        self.check_fault()?;
+        self.stop_if_reversing(Direction::Clockwise)
+            .await
+            .map_err(MotorError::Pwm)?;
+        let Ok(()) = self.ina.set_high();
+        let Ok(()) = self.inb.set_low();
+        self.direction = Direction::Clockwise;
+        Ok(())
     }
 
-    pub fn counter_clockwise(&mut self) {
-        self.ina.set_low();
-        self.inb.set_high();
+    /// Reversing always ramps down and brakes for the dwell time first, leaving the motor stopped
+    #[allow(dead_code)] // Only the peristaltic pumps are on H-bridges, and they only run forwards
+    pub async fn counter_clockwise(&mut self) -> Result<(), MotorError<PWM::Error>> {
+        self.check_fault()?;
+        self.stop_if_reversing(Direction::CounterClockwise)
+            .await
+            .map_err(MotorError::Pwm)?;
+        let Ok(()) = self.ina.set_low();
+        let Ok(()) = self.inb.set_high();
+        self.direction = Direction::CounterClockwise;
+        Ok(())
     }
 
+    /// Stops immediately, without ramping down
     pub fn brake(&mut self) {
-        self.ina.set_low();
-        self.inb.set_low();
+        let Ok(()) = self.ina.set_low();
+        let Ok(()) = self.inb.set_low();
+        self.direction = Direction::Brake;
     }
 
-    /// Sets speed in percent
-    pub fn set_speed(&mut self, speed: u8) -> Result<(), PWM::Error> {
-        self.pwm.set_duty_cycle_percent(speed)?;
+    /// Ramps down to a stop, then brakes
+    pub async fn soft_stop(&mut self) -> Result<(), PWM::Error> {
+        self.ramp_to_duty(0).await?;
+        self.brake();
+        Ok(())
+    }
+
+    /// Keeps the motor running for `duration`, stopping it early if the current is out of range
+    pub async fn run_monitored(
+        &mut self,
+        duration: Duration,
+    ) -> Result<(), MotorError<PWM::Error>> {
+        let end = Instant::now() + duration;
+        if self.current.is_none() {
+            Timer::at(end).await;
+            return Ok(());
+        }
+
+        Timer::after(SPIN_UP.min(duration)).await;
+        while Instant::now() < end {
+            let fault = match self.current.as_mut() {
+                Some(current) => current.check().await,
+                None => None,
+            };
+            if let Some(fault) = fault {
+                self.brake();
+                self.set_duty(0).map_err(MotorError::Pwm)?;
+                self.fault = Some(fault);
+                return Err(MotorError::Fault(fault));
+            }
+            Timer::at((Instant::now() + CURRENT_SAMPLE_INTERVAL).min(end)).await;
+        }
         Ok(())
     }
 
+    /// The fault that stopped the motor, it won't start again until this is cleared
+    pub fn fault(&self) -> Option<MotorFault> {
+        self.fault
+    }
+
+    pub fn clear_fault(&mut self) {
+        self.fault = None;
+    }
+
+    pub fn current_sense_mut(&mut self) -> Option<&mut CurrentSense<'a>> {
+        self.current.as_mut()
+    }
+
+    /// Sets speed in percent, skipping the ramp
+    #[allow(dead_code)] // The firmware always goes through `ramp_to_speed`
+    pub fn set_speed(&mut self, speed: u8) -> Result<(), PWM::Error> {
+        self.set_duty(self.duty_for_percent(speed))
+    }
+
+    /// Ramps to a speed in percent using the motor's ramp profile
+    pub async fn ramp_to_speed(&mut self, speed: u8) -> Result<(), PWM::Error> {
+        self.ramp_to_duty(self.duty_for_percent(speed)).await
+    }
+
+    /// Current speed in percent, 0 while braked
+    pub fn speed(&self) -> u8 {
+        if self.direction == Direction::Brake {
+            return 0;
+        }
+        let max = self.get_max_duty().max(1) as u32;
+        ((self.duty as u32 * 100 + max / 2) / max) as u8
+    }
+
     /// Returns the maximum
     pub fn get_max_duty(&self) -> u16 {
         self.pwm.max_duty_cycle()
@@ -50,6 +199,108 @@ impl<'a, PWM: SetDutyCycle> Motor<'a, PWM> {
     /// Changes the motor speed
     pub fn set_duty(&mut self, duty: u16) -> Result<(), PWM::Error> {
         self.pwm.set_duty_cycle(duty)?;
+        self.duty = duty;
         Ok(())
     }
+
+    /// Changes the motor speed gradually using the motor's ramp profile
+    pub async fn ramp_to_duty(&mut self, duty: u16) -> Result<(), PWM::Error> {
+        let rate = if duty > self.duty {
+            self.ramp.accel_percent_per_sec
+        } else {
+            self.ramp.decel_percent_per_sec
+        };
+        if rate == 0 {
+            return self.set_duty(duty);
+        }
+
+        let max = self.get_max_duty() as u32;
+        let step = (max * rate as u32 * RAMP_TICK_MS as u32 / 100_000).max(1) as u16;
+        while self.duty != duty {
+            let next = if duty > self.duty {
+                self.duty.saturating_add(step).min(duty)
+            } else {
+                self.duty.saturating_sub(step).max(duty)
+            };
+            self.set_duty(next)?;
+            Timer::after_millis(RAMP_TICK_MS).await;
+        }
+        Ok(())
+    }
+
+    fn duty_for_percent(&self, speed: u8) -> u16 {
+        (self.get_max_duty() as u32 * speed.min(100) as u32 / 100) as u16
+    }
+
+    fn check_fault(&self) -> Result<(), MotorError<PWM::Error>> {
+        match self.fault {
+            Some(fault) => Err(MotorError::Fault(fault)),
+            None => Ok(()),
+        }
+    }
+
+    async fn stop_if_reversing(&mut self, direction: Direction) -> Result<(), PWM::Error> {
+        if self.direction != direction && self.direction != Direction::Brake {
+            self.soft_stop().await?;
+            Timer::after(self.ramp.reverse_dwell).await;
+        }
+        Ok(())
+    }
+}
+
+impl<PWM: SetDutyCycle, PIN: OutputPin<Error = Infallible>> Actuator for Motor<'_, PWM, PIN> {
+    /// Ramps to `percent` clockwise, or ramps down and brakes at 0
+    async fn set_level(&mut self, percent: u8) -> Result<(), ActuatorError> {
+        if percent == 0 {
+            return self.soft_stop().await.map_err(|_| ActuatorError::Hardware);
+        }
+        self.clockwise().await?;
+        self.ramp_to_speed(percent)
+            .await
+            .map_err(|_| ActuatorError::Hardware)
+    }
+
+    fn level(&self) -> u8 {
+        self.speed()
+    }
+
+    /// Uses current sensing during the run when the motor has it
+    async fn run_for(&mut self, percent: u8, duration: Duration) -> Result<(), ActuatorError> {
+        let result = match self.set_level(percent).await {
+            Ok(()) => self
+                .run_monitored(duration)
+                .await
+                .map_err(ActuatorError::from),
+            Err(e) => Err(e),
+        };
+        self.soft_stop()
+            .await
+            .map_err(|_| ActuatorError::Hardware)?;
+        result
+    }
+
+    fn fault(&self) -> Option<MotorFault> {
+        Motor::fault(self)
+    }
+
+    fn clear_fault(&mut self) {
+        Motor::clear_fault(self)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum MotorError<E: core::fmt::Debug> {
+    #[error("PWM error: {0:?}")]
+    Pwm(E),
+    #[error("Motor fault: {0:?}")]
+    Fault(MotorFault),
+}
+
+impl<E: core::fmt::Debug> From<MotorError<E>> for ActuatorError {
+    fn from(e: MotorError<E>) -> Self {
+        match e {
+            MotorError::Pwm(_) => ActuatorError::Hardware,
+            MotorError::Fault(fault) => ActuatorError::Fault(fault),
+        }
+    }
 }
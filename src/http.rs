@@ -0,0 +1,385 @@
+// A small HTTP/1.1 request parser that never panics on bad input.
+// It doesn't depend on anything embassy, so it can be fuzzed on the host.
+use core::str::from_utf8;
+
+use heapless::Vec;
+use thiserror::Error;
+
+pub const MAX_HEADERS: usize = 24;
+// Longest request target (path and query) that is accepted
+pub const MAX_TARGET_LEN: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Options,
+}
+
+impl Method {
+    pub fn parse(method: &str) -> Result<Method, ParseError> {
+        match method {
+            "GET" => Ok(Method::Get),
+            "HEAD" => Ok(Method::Head),
+            "POST" => Ok(Method::Post),
+            "PUT" => Ok(Method::Put),
+            "DELETE" => Ok(Method::Delete),
+            "OPTIONS" => Ok(Method::Options),
+            // A method has to be a token, anything else is garbage
+            m if !m.is_empty() && m.bytes().all(|b| b.is_ascii_uppercase()) => {
+                Err(ParseError::NotImplemented)
+            }
+            _ => Err(ParseError::BadRequest),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Header<'a> {
+    pub name: &'a str,
+    pub value: &'a str,
+}
+
+#[derive(Debug)]
+pub struct Request<'a> {
+    pub method: Method,
+    pub version: Version,
+    // Path without the query string
+    pub path: &'a str,
+    // Everything after the '?', empty if there wasn't one
+    pub query: &'a str,
+    pub headers: Vec<Header<'a>, MAX_HEADERS>,
+    pub body: &'a [u8],
+    // Bytes used by this request, anything after it belongs to the next one
+    pub len: usize,
+}
+
+impl<'a> Request<'a> {
+    /// Header names are case insensitive
+    pub fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .map(|h| h.value)
+    }
+
+    /// Whether the client wants the connection kept open afterwards. HTTP/1.0 closes it
+    /// unless the client asks otherwise, HTTP/1.1 keeps it unless asked to close.
+    pub fn keep_alive(&self) -> bool {
+        let has_option = |option: &str| {
+            self.header("Connection")
+                .is_some_and(|c| c.split(',').any(|o| o.trim().eq_ignore_ascii_case(option)))
+        };
+        match self.version {
+            Version::Http10 => has_option("keep-alive"),
+            Version::Http11 => !has_option("close"),
+        }
+    }
+}
+
+/// Finds `name` in a query string like "a=1&b=2"
+pub fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Error)]
+pub enum ParseError {
+    /// Not an error, more has to be read before the request can be parsed
+    #[error("Request is incomplete")]
+    Incomplete,
+    #[error("Bad request")]
+    BadRequest,
+    #[error("Request is too large")]
+    PayloadTooLarge,
+    #[error("Request target is too long")]
+    UriTooLong,
+    #[error("Too many or too large headers")]
+    HeadersTooLarge,
+    #[error("Not implemented")]
+    NotImplemented,
+}
+
+impl ParseError {
+    /// Status line for the error response
+    pub fn status(&self) -> &'static str {
+        match self {
+            ParseError::Incomplete | ParseError::BadRequest => "400 Bad Request",
+            ParseError::PayloadTooLarge => "413 Content Too Large",
+            ParseError::UriTooLong => "414 URI Too Long",
+            ParseError::HeadersTooLarge => "431 Request Header Fields Too Large",
+            ParseError::NotImplemented => "501 Not Implemented",
+        }
+    }
+}
+
+/// Parses the request at the start of `buf`, which holds everything read so far.
+/// `max_len` is the size of the read buffer, a request that can't fit in it is rejected
+/// instead of waiting for bytes that will never fit.
+pub fn parse(buf: &[u8], max_len: usize) -> Result<Request<'_>, ParseError> {
+    // Tolerates blank lines before a request, as RFC 9112 asks
+    let start = buf
+        .iter()
+        .position(|b| *b != b'\r' && *b != b'\n')
+        .unwrap_or(buf.len());
+    let buf_from_start = &buf[start..];
+
+    let Some(line_end) = find(buf_from_start, b"\r\n") else {
+        return Err(incomplete_line(buf_from_start, buf.len(), max_len));
+    };
+    let (method, version, path, query) = parse_request_line(&buf_from_start[..line_end])?;
+
+    let Some(head_end) = find(buf_from_start, b"\r\n\r\n") else {
+        return Err(if buf.len() >= max_len {
+            ParseError::HeadersTooLarge
+        } else {
+            ParseError::Incomplete
+        });
+    };
+    let mut headers = Vec::new();
+    // Without any headers the request line's CRLF is the start of the blank line, which
+    // leaves line_end + 2 past head_end
+    if let Some(header_block) = buf_from_start.get(line_end + 2..head_end) {
+        for line in header_block.split(|b| *b == b'\n') {
+            // The block stops short of the last header's CRLF
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            headers
+                .push(parse_header(line)?)
+                .map_err(|_| ParseError::HeadersTooLarge)?;
+        }
+    }
+
+    let head_len = start + head_end + 4;
+    let request = Request {
+        method,
+        version,
+        path,
+        query,
+        headers,
+        body: &[],
+        len: head_len,
+    };
+    if request.header("Transfer-Encoding").is_some() {
+        return Err(ParseError::NotImplemented);
+    }
+    let body_len = match request.header("Content-Length") {
+        Some(len) => len.parse::<usize>().map_err(|_| ParseError::BadRequest)?,
+        None => 0,
+    };
+    if body_len > max_len.saturating_sub(head_len) {
+        return Err(ParseError::PayloadTooLarge);
+    }
+    let end = head_len + body_len;
+    if buf.len() < end {
+        return Err(ParseError::Incomplete);
+    }
+    Ok(Request {
+        body: &buf[head_len..end],
+        len: end,
+        ..request
+    })
+}
+
+// Decides what an unterminated request line means
+fn incomplete_line(line: &[u8], buffered: usize, max_len: usize) -> ParseError {
+    // Method, space and target, so the target alone is already too long
+    if line.len() > MAX_TARGET_LEN + "OPTIONS ".len() {
+        ParseError::UriTooLong
+    } else if buffered >= max_len {
+        ParseError::BadRequest
+    } else {
+        ParseError::Incomplete
+    }
+}
+
+fn parse_request_line(line: &[u8]) -> Result<(Method, Version, &str, &str), ParseError> {
+    let line = from_utf8(line).map_err(|_| ParseError::BadRequest)?;
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(ParseError::BadRequest);
+    };
+    if target.len() > MAX_TARGET_LEN {
+        return Err(ParseError::UriTooLong);
+    }
+    let version = match version {
+        "HTTP/1.1" => Version::Http11,
+        "HTTP/1.0" => Version::Http10,
+        _ => return Err(ParseError::BadRequest),
+    };
+    let method = Method::parse(method)?;
+    if !target.starts_with('/') {
+        return Err(ParseError::BadRequest);
+    }
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    Ok((method, version, path, query))
+}
+
+fn parse_header(line: &[u8]) -> Result<Header<'_>, ParseError> {
+    let line = from_utf8(line).map_err(|_| ParseError::BadRequest)?;
+    let (name, value) = line.split_once(':').ok_or(ParseError::BadRequest)?;
+    // No whitespace is allowed between the name and the colon
+    if name.is_empty()
+        || name
+            .bytes()
+            .any(|b| b.is_ascii_whitespace() || b.is_ascii_control())
+    {
+        return Err(ParseError::BadRequest);
+    }
+    Ok(Header {
+        name,
+        value: value.trim_matches([' ', '\t']),
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_LEN: usize = 512;
+
+    #[test]
+    fn parses_request_without_headers() {
+        let req = parse(b"GET /status?x=1 HTTP/1.1\r\n\r\n", MAX_LEN).unwrap();
+        assert_eq!(req.method, Method::Get);
+        assert_eq!(req.path, "/status");
+        assert_eq!(req.query, "x=1");
+        assert!(req.headers.is_empty());
+        assert!(req.keep_alive());
+    }
+
+    #[test]
+    fn parses_headers_and_body() {
+        let buf = b"POST /config HTTP/1.1\r\nHost: pico\r\nContent-Length: 4\r\n\r\n{}{}";
+        let req = parse(buf, MAX_LEN).unwrap();
+        assert_eq!(req.header("host"), Some("pico"));
+        assert_eq!(req.body, b"{}{}");
+        assert_eq!(req.len, buf.len());
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_a_split_read() {
+        let buf = b"POST /config HTTP/1.1\r\nContent-Length: 4\r\n\r\n{}{}";
+        for split in 0..buf.len() {
+            assert_eq!(
+                parse(&buf[..split], MAX_LEN).unwrap_err(),
+                ParseError::Incomplete
+            );
+        }
+        assert!(parse(buf, MAX_LEN).is_ok());
+    }
+
+    #[test]
+    fn leaves_a_pipelined_request_alone() {
+        let buf = b"GET / HTTP/1.1\r\n\r\nGET /status HTTP/1.1\r\n\r\n";
+        let req = parse(buf, MAX_LEN).unwrap();
+        assert_eq!(req.path, "/");
+        assert_eq!(parse(&buf[req.len..], MAX_LEN).unwrap().path, "/status");
+    }
+
+    #[test]
+    fn rejects_oversize_target() {
+        let mut buf = [b'a'; MAX_LEN];
+        buf[..5].copy_from_slice(b"GET /");
+        assert_eq!(
+            parse(&buf[..MAX_TARGET_LEN + 16], MAX_LEN).unwrap_err(),
+            ParseError::UriTooLong
+        );
+    }
+
+    #[test]
+    fn rejects_oversize_body() {
+        let buf = b"POST /config HTTP/1.1\r\nContent-Length: 1000\r\n\r\n";
+        assert_eq!(
+            parse(buf, MAX_LEN).unwrap_err(),
+            ParseError::PayloadTooLarge
+        );
+    }
+
+    #[test]
+    fn rejects_headers_that_never_end() {
+        let mut buf = [b'a'; MAX_LEN];
+        buf[..18].copy_from_slice(b"GET / HTTP/1.1\r\nX:");
+        assert_eq!(
+            parse(&buf, MAX_LEN).unwrap_err(),
+            ParseError::HeadersTooLarge
+        );
+    }
+
+    #[test]
+    fn rejects_transfer_encoding() {
+        let buf = b"POST /config HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(parse(buf, MAX_LEN).unwrap_err(), ParseError::NotImplemented);
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        for buf in [
+            &b"get / HTTP/1.1\r\n\r\n"[..],
+            b"GET / HTTP/2\r\n\r\n",
+            b"GET status HTTP/1.1\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost : pico\r\n\r\n",
+            b"GET / HTTP/1.1\r\nContent-Length: -1\r\n\r\n",
+        ] {
+            assert_eq!(parse(buf, MAX_LEN).unwrap_err(), ParseError::BadRequest);
+        }
+        assert_eq!(
+            parse(b"PATCH / HTTP/1.1\r\n\r\n", MAX_LEN).unwrap_err(),
+            ParseError::NotImplemented
+        );
+    }
+
+    // A cheap stand-in for a fuzzer: corrupt a valid request in every way a small PRNG
+    // comes up with and make sure nothing panics or claims more than it was given
+    #[test]
+    fn survives_corrupted_requests() {
+        let valid = b"POST /config?a=b HTTP/1.1\r\nHost: pico\r\nContent-Length: 2\r\n\r\n{}";
+        let mut seed: u32 = 0x1234_5678;
+        let mut next = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as usize
+        };
+        for _ in 0..100_000 {
+            let mut buf = *valid;
+            for _ in 0..next() % 4 + 1 {
+                let i = next() % buf.len();
+                buf[i] = next() as u8;
+            }
+            let len = next() % (buf.len() + 1);
+            if let Ok(req) = parse(&buf[..len], 64) {
+                assert!(req.len <= len);
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_http_1_0_alive_only_when_asked() {
+        let close = parse(b"GET / HTTP/1.0\r\n\r\n", MAX_LEN).unwrap();
+        assert!(!close.keep_alive());
+        let keep = parse(b"GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n", MAX_LEN).unwrap();
+        assert!(keep.keep_alive());
+        let close = parse(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n", MAX_LEN).unwrap();
+        assert!(!close.keep_alive());
+    }
+}
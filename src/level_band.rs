@@ -0,0 +1,159 @@
+// Water level bands and how the float switches map onto them. Kept apart from the sensors in
+// hardware/level.rs so the mapping can be tested on the host.
+use log::*;
+use serde::{Deserialize, Serialize};
+
+/// Where the water is relative to the level marks (the float switch heights)
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LevelBand {
+    #[default]
+    Unknown,
+    // Below the low mark, pumps could run dry
+    BelowLow,
+    // Between low and normal, due a top-up
+    Low,
+    Normal,
+    // At or above the full mark
+    High,
+    // Something is about to spill
+    Overflow,
+}
+
+impl LevelBand {
+    /// There's enough water for the pumps
+    pub fn above_low(self) -> bool {
+        matches!(
+            self,
+            LevelBand::Low | LevelBand::Normal | LevelBand::High | LevelBand::Overflow
+        )
+    }
+
+    pub fn needs_top_up(self) -> bool {
+        matches!(self, LevelBand::BelowLow | LevelBand::Low)
+    }
+
+    pub fn is_full(self) -> bool {
+        matches!(self, LevelBand::High | LevelBand::Overflow)
+    }
+}
+
+/// Whether each float switch is wet, `None` for any that aren't fitted
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct FloatReadings {
+    pub low: Option<bool>,
+    pub normal: Option<bool>,
+    pub high: Option<bool>,
+    pub overflow: Option<bool>,
+}
+
+impl FloatReadings {
+    /// The band from the highest wet switch, or `Unknown` if none are fitted
+    pub fn band(&self) -> LevelBand {
+        // Without a normal switch the water is low until it reaches the next switch up, so a
+        // top-up starts before the pumps are stopped at the low mark. A lone low switch has
+        // nothing to fill up to, so anything above it counts as normal.
+        let above_low_fitted =
+            self.normal.is_some() || self.high.is_some() || self.overflow.is_some();
+        let marks = [
+            (self.overflow, LevelBand::Overflow),
+            (self.high, LevelBand::High),
+            (self.normal, LevelBand::Normal),
+            (
+                self.low,
+                if above_low_fitted {
+                    LevelBand::Low
+                } else {
+                    LevelBand::Normal
+                },
+            ),
+        ];
+
+        let mut band = None;
+        for (state, mark_band) in marks {
+            match (state, band) {
+                (Some(true), None) => band = Some(mark_band),
+                // A dry switch below a wet one is stuck or has fallen off
+                (Some(false), Some(found)) => {
+                    warn!("Float switch below {:?} reads dry", found);
+                }
+                _ => {}
+            }
+        }
+        match band {
+            Some(band) => band,
+            None if self.low.is_some() => LevelBand::BelowLow,
+            None => LevelBand::Unknown,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uses_the_highest_wet_switch() {
+        let mut floats = FloatReadings {
+            low: Some(false),
+            normal: Some(false),
+            high: Some(false),
+            overflow: Some(false),
+        };
+        assert_eq!(floats.band(), LevelBand::BelowLow);
+        floats.low = Some(true);
+        assert_eq!(floats.band(), LevelBand::Low);
+        floats.normal = Some(true);
+        assert_eq!(floats.band(), LevelBand::Normal);
+        floats.high = Some(true);
+        assert_eq!(floats.band(), LevelBand::High);
+        floats.overflow = Some(true);
+        assert_eq!(floats.band(), LevelBand::Overflow);
+
+        // A stuck switch below doesn't hide a wet one above
+        floats.normal = Some(false);
+        assert_eq!(floats.band(), LevelBand::Overflow);
+    }
+
+    #[test]
+    fn tops_up_between_low_and_high_without_a_normal_switch() {
+        let mut floats = FloatReadings {
+            low: Some(false),
+            high: Some(false),
+            ..FloatReadings::default()
+        };
+        assert_eq!(floats.band(), LevelBand::BelowLow);
+        assert!(!floats.band().above_low());
+
+        // Pumps can still run, and the top-up starts long before they can't
+        floats.low = Some(true);
+        assert_eq!(floats.band(), LevelBand::Low);
+        assert!(floats.band().above_low());
+        assert!(floats.band().needs_top_up());
+
+        floats.high = Some(true);
+        assert_eq!(floats.band(), LevelBand::High);
+        assert!(!floats.band().needs_top_up());
+    }
+
+    #[test]
+    fn lone_low_switch_only_tops_up_below_it() {
+        let mut floats = FloatReadings {
+            low: Some(true),
+            ..FloatReadings::default()
+        };
+        assert_eq!(floats.band(), LevelBand::Normal);
+        floats.low = Some(false);
+        assert_eq!(floats.band(), LevelBand::BelowLow);
+    }
+
+    #[test]
+    fn unknown_without_switches() {
+        assert_eq!(FloatReadings::default().band(), LevelBand::Unknown);
+        let floats = FloatReadings {
+            high: Some(false),
+            ..FloatReadings::default()
+        };
+        assert_eq!(floats.band(), LevelBand::Unknown);
+    }
+}
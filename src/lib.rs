@@ -0,0 +1,11 @@
+// The parts of the firmware that don't depend on the RP2040, kept in a library so they can
+// be tested on the host
+#![no_std]
+
+pub mod actuator;
+pub mod auth;
+pub mod base64;
+pub mod http;
+pub mod level_band;
+pub mod sha1;
+pub mod websocket;
@@ -10,20 +10,26 @@ use embassy_embedded_hal::adapter::BlockingAsync;
 use embassy_executor::Spawner;
 use embassy_net::{Ipv4Cidr, StackResources};
 use embassy_rp::{
+    adc::{self, Adc},
     bind_interrupts,
     clocks::RoscRng,
-    config,
-    gpio::{Input, Level, Output},
+    flash::Flash,
+    gpio::{Input, Level, Output, Pull},
     i2c::I2c,
     peripherals::{I2C1, PIO0, USB},
     pio::Pio,
-    pwm::{Pwm, PwmOutput},
+    pwm::{self, Pwm},
     usb::Driver,
     watchdog::Watchdog,
 };
 use embassy_sync::mutex::Mutex;
 use embassy_time::{Duration, Timer};
-use hardware::motor::Motor;
+use hardware::{
+    actuator::{Interlocked, PwmDimmer, Relay, SolenoidValve},
+    current_sense::{CurrentSense, SharedAdc},
+    level::FloatSwitches,
+    motor::Motor,
+};
 use heapless::Vec;
 use log::*;
 use panic_reset as _;
@@ -31,6 +37,8 @@ use rand_core::RngCore;
 use static_cell::StaticCell;
 use tasks::*;
 
+mod api;
+mod config;
 mod hardware;
 mod tasks;
 
@@ -38,6 +46,7 @@ bind_interrupts!(struct Irqs {
     PIO0_IRQ_0 => embassy_rp::pio::InterruptHandler<PIO0>;
     I2C1_IRQ => embassy_rp::i2c::InterruptHandler<I2C1>;
     USBCTRL_IRQ => embassy_rp::usb::InterruptHandler<USB>;
+    ADC_IRQ_FIFO => embassy_rp::adc::InterruptHandler;
 });
 
 #[cfg(feature = "notci")]
@@ -53,6 +62,26 @@ async fn main(spawner: Spawner) {
     spawner.must_spawn(logger(p.USB));
     info!("Begin logging");
 
+    // Load the persisted config before anything that depends on it is started
+    let mut config_store = config::ConfigStore::new(Flash::new_blocking(p.FLASH));
+    // Holding this pin low during boot restores the default config
+    // TODO: MAKE SURE this is the CORRECT PIN
+    let factory_reset_pin = Input::new(p.PIN_22, Pull::Up);
+    if factory_reset_pin.is_low()
+        && let Err(e) = config_store.factory_reset()
+    {
+        error!("Factory reset failed: {}", e);
+    }
+    let cfg = config_store.load();
+    // Before anything that could switch a pump on is started
+    if let Some(emergency) = cfg.emergency {
+        error!("{:?} is still latched from before the reset", emergency);
+        hardware::actuator::emergency_stop();
+    }
+    *config::CONFIG.lock().await = cfg;
+    static CONFIG_STORE: StaticCell<config::SharedConfigStore> = StaticCell::new();
+    let config_store = CONFIG_STORE.init(Mutex::new(config_store));
+
     let mut rng = RoscRng;
 
     let fw = include_bytes!("../cyw43-firmware/43439A0.bin");
@@ -91,13 +120,13 @@ async fn main(spawner: Spawner) {
     control.gpio_set(0, true).await;
 
     let config = embassy_net::Config::ipv4_static(embassy_net::StaticConfigV4 {
-        address: Ipv4Cidr::new(Ipv4Addr::new(10, 0, 0, 21), 24),
+        address: Ipv4Cidr::new(Ipv4Addr::from(cfg.ip_address), cfg.prefix_len),
         dns_servers: Vec::new(),
-        gateway: Some(Ipv4Addr::new(10, 0, 0, 1)),
+        gateway: Some(Ipv4Addr::from(cfg.gateway)),
     });
 
-    // Init the network stack
-    static RESOURCES: StaticCell<StackResources<3>> = StaticCell::new();
+    // Init the network stack, with a socket for each HTTP worker
+    static RESOURCES: StaticCell<StackResources<{ networking::SOCKET_COUNT }>> = StaticCell::new();
     let (stack, net_runner) = embassy_net::new(
         net_device,
         config,
@@ -107,7 +136,11 @@ async fn main(spawner: Spawner) {
     // Begin the cyw43 communication and start the server
     spawner
         .spawn(networking::begin_hosting_task(
-            spawner, net_runner, control, stack,
+            spawner,
+            net_runner,
+            control,
+            stack,
+            config_store,
         ))
         .unwrap();
 
@@ -117,13 +150,127 @@ async fn main(spawner: Spawner) {
     let i2c_bus = I2C_BUS.init(Mutex::new(i2c1));
     spawner.spawn(state::update_ec_state_task(i2c_bus)).unwrap();
     spawner.spawn(state::update_ph_state_task(i2c_bus)).unwrap();
+    spawner.spawn(history::history_task()).unwrap();
+
+    // Float switches at the level marks, there are no pins left for the normal and overflow marks.
+    // An analog or ultrasonic sensor can be passed as the `ContinuousLevel` to get litres.
+    // TODO: MAKE SURE these are the CORRECT PINS
+    let floats = FloatSwitches {
+        low: Some(Input::new(p.PIN_10, Pull::Down)),
+        normal: None,
+        high: Some(Input::new(p.PIN_5, Pull::Down)),
+        overflow: None,
+    };
+    spawner
+        .spawn(state::update_water_lvl_state_task(floats, None))
+        .unwrap();
+
+    // Floor leak probes, these stop every pump and valve if they get wet
+    // TODO: MAKE SURE this is the CORRECT PIN
+    let leak_probes = [Some(Input::new(p.PIN_28, Pull::Up)), None, None, None];
+    spawner
+        .spawn(leak::leak_task(leak_probes, config_store))
+        .unwrap();
+
+    // Current sensing for the pumps, only the pH pumps get it since PIN_28 went to a leak probe
+    static ADC: StaticCell<SharedAdc> = StaticCell::new();
+    let adc = ADC.init(Mutex::new(Adc::new(p.ADC, Irqs, adc::Config::default())));
+    spawner
+        .spawn(metrics::board_temperature_task(
+            adc,
+            adc::Channel::new_temp_sensor(p.ADC_TEMP_SENSOR),
+        ))
+        .unwrap();
+
+    // Dosing pumps, in the order of the `config::*_PUMP` indices
+    // TODO: MAKE SURE these are the CORRECT PINS
+    let dosing_pumps = [
+        // pH up
+        Motor::new(
+            Output::new(p.PIN_2, Level::Low),
+            Output::new(p.PIN_3, Level::Low),
+            Pwm::new_output_a(p.PWM_SLICE2, p.PIN_4, pwm::Config::default())
+                .split()
+                .0
+                .unwrap(),
+        )
+        .with_current_sense(CurrentSense::new(
+            adc,
+            adc::Channel::new_pin(p.PIN_26, Pull::None),
+            cfg.current_thresholds(),
+        )),
+        // pH down
+        Motor::new(
+            Output::new(p.PIN_6, Level::Low),
+            Output::new(p.PIN_7, Level::Low),
+            Pwm::new_output_a(p.PWM_SLICE4, p.PIN_8, pwm::Config::default())
+                .split()
+                .0
+                .unwrap(),
+        )
+        .with_current_sense(CurrentSense::new(
+            adc,
+            adc::Channel::new_pin(p.PIN_27, Pull::None),
+            cfg.current_thresholds(),
+        )),
+        // Part A
+        Motor::new(
+            Output::new(p.PIN_9, Level::Low),
+            Output::new(p.PIN_20, Level::Low),
+            Pwm::new_output_a(p.PWM_SLICE6, p.PIN_12, pwm::Config::default())
+                .split()
+                .0
+                .unwrap(),
+        ),
+        // Part B
+        Motor::new(
+            Output::new(p.PIN_13, Level::Low),
+            Output::new(p.PIN_16, Level::Low),
+            Pwm::new_output_a(p.PWM_SLICE1, p.PIN_18, pwm::Config::default())
+                .split()
+                .0
+                .unwrap(),
+        ),
+        // Part C
+        Motor::new(
+            Output::new(p.PIN_17, Level::Low),
+            Output::new(p.PIN_19, Level::Low),
+            Pwm::new_output_a(p.PWM_SLICE0, p.PIN_0, pwm::Config::default())
+                .split()
+                .0
+                .unwrap(),
+        ),
+    ];
+    spawner
+        .spawn(dose::dosing_task(dosing_pumps, config_store))
+        .unwrap();
+
+    // Grow light, use `GrowLight::Relay` for lights that can't be dimmed
     // TODO: MAKE SURE this is the CORRECT PIN
+    let grow_light = lighting::GrowLight::Dimmer(PwmDimmer::new(
+        Pwm::new_output_b(p.PWM_SLICE5, p.PIN_11, pwm::Config::default())
+            .split()
+            .1
+            .unwrap(),
+    ));
     spawner
-        .spawn(state::update_water_lvl_state_task(Input::new(
-            p.PIN_10,
-            embassy_rp::gpio::Pull::Down,
-        )))
+        .spawn(lighting::lighting_task(grow_light, config_store))
         .unwrap();
+
+    // Main pump for the flood tables, switched by a relay
+    // TODO: MAKE SURE this is the CORRECT PIN
+    let irrigation_pump = Interlocked::new(Relay::new(Output::new(p.PIN_21, Level::Low), false));
+    spawner
+        .spawn(irrigation::irrigation_task(irrigation_pump))
+        .unwrap();
+
+    // Reservoir top-up through a normally closed fill valve
+    // TODO: MAKE SURE this is the CORRECT PIN
+    let fill_valve = Interlocked::new(SolenoidValve::new(
+        Relay::new(Output::new(p.PIN_1, Level::Low), false),
+        false,
+    ));
+    spawner.spawn(top_up::top_up_task(fill_valve)).unwrap();
 }
 
 #[embassy_executor::task]
@@ -0,0 +1,144 @@
+// SHA-1 (RFC 3174), only needed for the WebSocket handshake. It's broken as a
+// cryptographic hash, so don't use it for anything to do with security.
+
+pub const DIGEST_LEN: usize = 20;
+
+const BLOCK_LEN: usize = 64;
+
+/// Hashes the concatenation of `parts`
+pub fn digest(parts: &[&[u8]]) -> [u8; DIGEST_LEN] {
+    let mut sha1 = Sha1::new();
+    for part in parts {
+        sha1.update(part);
+    }
+    sha1.finish()
+}
+
+struct Sha1 {
+    state: [u32; 5],
+    block: [u8; BLOCK_LEN],
+    block_len: usize,
+    // Bytes hashed so far
+    total_len: u64,
+}
+
+impl Sha1 {
+    fn new() -> Sha1 {
+        Sha1 {
+            state: [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0],
+            block: [0; BLOCK_LEN],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.total_len += data.len() as u64;
+        while !data.is_empty() {
+            let take = data.len().min(BLOCK_LEN - self.block_len);
+            self.block[self.block_len..self.block_len + take].copy_from_slice(&data[..take]);
+            self.block_len += take;
+            data = &data[take..];
+            if self.block_len == BLOCK_LEN {
+                compress(&mut self.state, &self.block);
+                self.block_len = 0;
+            }
+        }
+    }
+
+    fn finish(mut self) -> [u8; DIGEST_LEN] {
+        let bit_len = self.total_len * 8;
+        // A 1 bit, zeros up to 8 bytes short of a block, then the length in bits
+        self.update(&[0x80]);
+        while self.block_len != BLOCK_LEN - 8 {
+            self.update(&[0]);
+        }
+        self.update(&bit_len.to_be_bytes());
+
+        let mut digest = [0; DIGEST_LEN];
+        for (out, word) in digest.chunks_exact_mut(4).zip(self.state) {
+            out.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+}
+
+fn compress(state: &mut [u32; 5], block: &[u8; BLOCK_LEN]) {
+    let mut w = [0u32; 80];
+    for (i, word) in block.chunks_exact(4).enumerate() {
+        w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+    }
+    for i in 16..80 {
+        w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e] = *state;
+    for (i, word) in w.iter().enumerate() {
+        let (f, k) = match i {
+            0..20 => ((b & c) | (!b & d), 0x5A827999),
+            20..40 => (b ^ c ^ d, 0x6ED9EBA1),
+            40..60 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+            _ => (b ^ c ^ d, 0xCA62C1D6),
+        };
+        let temp = a
+            .rotate_left(5)
+            .wrapping_add(f)
+            .wrapping_add(e)
+            .wrapping_add(k)
+            .wrapping_add(*word);
+        e = d;
+        d = c;
+        c = b.rotate_left(30);
+        b = a;
+        a = temp;
+    }
+
+    for (s, v) in state.iter_mut().zip([a, b, c, d, e]) {
+        *s = s.wrapping_add(v);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(digest: [u8; DIGEST_LEN]) -> heapless::String<{ DIGEST_LEN * 2 }> {
+        let mut out = heapless::String::new();
+        for b in digest {
+            core::fmt::Write::write_fmt(&mut out, format_args!("{:02x}", b)).unwrap();
+        }
+        out
+    }
+
+    // The test vectors from RFC 3174 and FIPS 180
+    #[test]
+    fn matches_the_standard_vectors() {
+        assert_eq!(hex(digest(&[])), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(
+            hex(digest(&[b"abc"])),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        // Long enough that the length has to go in a second block
+        assert_eq!(
+            hex(digest(&[
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            ])),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+        let a = [b'a'; 1000];
+        assert_eq!(
+            hex(digest(&[&a[..]; 1000])),
+            "34aa973cd4c4daa4f61eeb2bdbad27316534016f"
+        );
+    }
+
+    #[test]
+    fn splitting_the_input_makes_no_difference() {
+        let input = [0x5a; 200];
+        let whole = digest(&[&input]);
+        for split in 0..input.len() {
+            let (a, b) = input.split_at(split);
+            assert_eq!(digest(&[a, b]), whole);
+        }
+    }
+}
@@ -0,0 +1,104 @@
+// Keeps track of the wall clock time, which the Pico has no battery backed source for
+use core::net::Ipv4Addr;
+
+use embassy_futures::select::{Either, select};
+use embassy_net::{
+    IpAddress, IpEndpoint, Stack,
+    udp::{PacketMetadata, UdpSocket},
+};
+use embassy_time::{Instant, Timer};
+use log::*;
+use portable_atomic::{AtomicU64, Ordering};
+
+use crate::config::CONFIG;
+
+const NTP_PORT: u16 = 123;
+const LOCAL_PORT: u16 = 12300;
+// Seconds between the NTP epoch (1900) and the unix epoch (1970)
+const NTP_TO_UNIX_SECS: u64 = 2_208_988_800;
+const RESPONSE_TIMEOUT_SECS: u64 = 5;
+const RETRY_SECS: u64 = 30;
+const RESYNC_SECS: u64 = 3600;
+const SECS_PER_DAY: u64 = 86_400;
+
+// Unix time at boot, 0 until the first sync
+static UNIX_AT_BOOT: AtomicU64 = AtomicU64::new(0);
+
+/// Seconds since the unix epoch, or `None` if the clock hasn't been synced yet
+pub fn unix_time() -> Option<u64> {
+    unix_time_at(Instant::now())
+}
+
+/// The unix time an `Instant` happened at, or `None` if the clock hasn't been synced yet
+pub fn unix_time_at(instant: Instant) -> Option<u64> {
+    match UNIX_AT_BOOT.load(Ordering::Relaxed) {
+        0 => None,
+        boot => Some(boot + instant.as_secs()),
+    }
+}
+
+/// Seconds since local midnight, using the configured UTC offset
+pub fn local_secs_of_day(utc_offset_mins: i32) -> Option<u32> {
+    let local = unix_time()? as i64 + utc_offset_mins as i64 * 60;
+    Some(local.rem_euclid(SECS_PER_DAY as i64) as u32)
+}
+
+#[embassy_executor::task]
+pub async fn ntp_task(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0; 128];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0; 128];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(LOCAL_PORT) {
+        error!("Failed to bind NTP socket: {:?}", e);
+        return;
+    }
+
+    loop {
+        let server = Ipv4Addr::from(CONFIG.lock().await.ntp_server);
+        let next = match sync(&socket, server).await {
+            Some(unix) => {
+                UNIX_AT_BOOT.store(unix - Instant::now().as_secs(), Ordering::Relaxed);
+                info!("Clock synced to {}", unix);
+                RESYNC_SECS
+            }
+            None => {
+                warn!("No response from NTP server {}", server);
+                RETRY_SECS
+            }
+        };
+        Timer::after_secs(next).await;
+    }
+}
+
+// Sends one SNTP request and returns the server's unix time
+async fn sync(socket: &UdpSocket<'_>, server: Ipv4Addr) -> Option<u64> {
+    // LI = 0, version 4, mode 3 (client)
+    let mut packet = [0u8; 48];
+    packet[0] = 0x23;
+    let endpoint = IpEndpoint::new(IpAddress::Ipv4(server), NTP_PORT);
+    socket.send_to(&packet, endpoint).await.ok()?;
+
+    let response = select(
+        socket.recv_from(&mut packet),
+        Timer::after_secs(RESPONSE_TIMEOUT_SECS),
+    )
+    .await;
+    let (len, _) = match response {
+        Either::First(result) => result.ok()?,
+        Either::Second(_) => return None,
+    };
+    if len < 48 {
+        return None;
+    }
+    // Seconds part of the transmit timestamp
+    let ntp_secs = u32::from_be_bytes([packet[40], packet[41], packet[42], packet[43]]) as u64;
+    ntp_secs.checked_sub(NTP_TO_UNIX_SECS)
+}
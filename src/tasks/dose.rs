@@ -1 +1,646 @@
 // Here, all the dosing of pH adjuster and fertilizer happens
+use embassy_futures::select::{Either, select};
+use embassy_rp::pwm::PwmOutput;
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, signal::Signal,
+};
+use embassy_time::{Duration, Instant, Timer};
+use log::*;
+use thiserror::Error;
+
+use crate::{
+    config::{
+        self, CONFIG, Config, PART_A_PUMP, PART_B_PUMP, PART_C_PUMP, PH_DOWN_PUMP, PH_UP_PUMP,
+        PUMP_COUNT, SharedConfigStore,
+    },
+    hardware::{
+        actuator::{ActuatorError, Interlocked, MotorFault},
+        dosing_pump::{CalibrationRun, DosingPump},
+        motor::Motor,
+    },
+    tasks::{
+        maintenance::in_maintenance,
+        metrics,
+        state::{DosingLockout, EcState, HydroponicState, MACHINE_STATE, PhState, update_state},
+    },
+};
+
+pub type PumpMotor = Motor<'static, PwmOutput<'static>>;
+
+// How often the controllers check for a new reading
+const POLL_INTERVAL_SECS: u64 = 10;
+// Anything smaller can't be dispensed accurately by the pumps
+const MIN_DOSE_ML: f32 = 0.2;
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+// Dosing locks out if a dose raises EC by less than this fraction of what was expected
+const MIN_EC_RISE_FRACTION: f32 = 0.5;
+// or moves pH the wrong way by more than this
+const PH_WRONG_WAY_TOLERANCE: f32 = 0.05;
+const SAVE_DISPENSED_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Tracks how much has been dosed in the current 24 hour window
+struct DailyTotal {
+    window_start: Instant,
+    dosed_ml: f32,
+}
+
+impl DailyTotal {
+    fn new() -> Self {
+        DailyTotal {
+            window_start: Instant::now(),
+            dosed_ml: 0.0,
+        }
+    }
+
+    // How much more can be dosed before hitting `max_ml` for today
+    fn remaining(&mut self, max_ml: f32) -> f32 {
+        if self.window_start.elapsed() >= DAY {
+            self.window_start = Instant::now();
+            self.dosed_ml = 0.0;
+        }
+        (max_ml - self.dosed_ml).max(0.0)
+    }
+
+    fn add(&mut self, ml: f32) {
+        self.dosed_ml += ml;
+    }
+}
+
+// Proportional to how far pH is from the target, capped by the per-dose and daily limits
+fn ph_dose_volume(error: f32, config: &Config, remaining_today: f32) -> Option<f32> {
+    let ml = (error * config.ph_ml_per_unit)
+        .min(config.ph_max_dose_ml)
+        .min(remaining_today);
+    if ml < MIN_DOSE_ML { None } else { Some(ml) }
+}
+
+// Enough concentrate to close the EC deficit in the whole reservoir, capped by the limits
+fn ec_dose_volume(deficit: f32, litres: f32, config: &Config, remaining_today: f32) -> Option<f32> {
+    let ml = (deficit * litres / config.nutrient_ec_per_ml_per_l)
+        .min(config.ec_max_dose_ml)
+        .min(remaining_today);
+    if ml < MIN_DOSE_ML { None } else { Some(ml) }
+}
+
+// A dosing pump wrapped in the hard safety limits from the config
+struct GuardedPump {
+    // Index into `Config::pump_calibration`
+    index: usize,
+    name: &'static str,
+    pump: DosingPump<Interlocked<PumpMotor>>,
+    daily: DailyTotal,
+    last_run: Option<Instant>,
+}
+
+impl GuardedPump {
+    // Checks a dose against the limits without running the pump
+    fn check(&mut self, ml: f32, config: &Config) -> Result<(), DoseError> {
+        if let Some(fault) = self.pump.fault() {
+            return Err(DoseError::Fault(fault));
+        }
+        let min_interval = Duration::from_secs(config.pump_min_interval_secs as u64);
+        if self.last_run.is_some_and(|t| t.elapsed() < min_interval) {
+            return Err(DoseError::TooSoon);
+        }
+        if ml > self.daily.remaining(config.pump_max_daily_ml) {
+            return Err(DoseError::DailyLimit);
+        }
+        Ok(())
+    }
+
+    // Dispenses `ml`, never running for longer than the configured maximum.
+    // Returns how much actually went in, which is less if the run was capped.
+    async fn dispense(&mut self, ml: f32, config: &Config) -> Result<f32, DoseError> {
+        self.check(ml, config)?;
+
+        let max_run = Duration::from_secs(config.pump_max_run_secs as u64);
+        let dispensed_before = self.pump.dispensed_ml();
+        let result = if self.pump.run_time_for(ml) > max_run {
+            warn!(
+                "{} pump run capped at {}s",
+                self.name, config.pump_max_run_secs
+            );
+            self.pump.run_for(max_run).await
+        } else {
+            self.pump.dispense(ml).await
+        };
+        self.last_run = Some(Instant::now());
+        // Whatever ran counts against the limit, even if the run was cut short
+        let dispensed = self.pump.dispensed_ml() - dispensed_before;
+        self.daily.add(dispensed);
+        metrics::count_dose(self.index, dispensed);
+        self.publish().await;
+        result.map(|()| dispensed).map_err(|e| match e {
+            ActuatorError::Fault(fault) => {
+                error!("{} stopped: {:?}", self.name, fault);
+                DoseError::Fault(fault)
+            }
+            ActuatorError::Hardware => DoseError::Hardware,
+            ActuatorError::EmergencyStop => DoseError::EmergencyStop,
+        })
+    }
+
+    // Shares the dispensed volume and any fault through the machine state
+    async fn publish(&self) {
+        let (dispensed, fault) = (self.pump.dispensed_ml(), self.pump.fault());
+        update_state(|state| {
+            state.pump_dispensed_ml[self.index] = dispensed;
+            state.pump_faults[self.index] = fault;
+        })
+        .await;
+    }
+}
+
+struct PhDoser {
+    daily: DailyTotal,
+    // pH before the last dose and whether it should have gone up
+    last_dose: Option<(f32, bool)>,
+}
+
+impl PhDoser {
+    // Checks the last dose moved pH the right way, returns false if dosing was locked out
+    async fn check_last_dose(&mut self, ph: PhState) -> bool {
+        // The check waits for a real reading, an unknown one doesn't say anything
+        if let (Some((before, raising)), PhState::Good(v) | PhState::High(v) | PhState::Low(v)) =
+            (self.last_dose, ph)
+        {
+            self.last_dose = None;
+            let moved = if raising { v - before } else { before - v };
+            if moved < -PH_WRONG_WAY_TOLERANCE {
+                error!(
+                    "pH went from {:.2} to {:.2} after dosing, locking out dosing",
+                    before, v
+                );
+                lock_out(DosingLockout::PhWrongWay).await;
+                return false;
+            }
+        }
+        true
+    }
+
+    // Returns true if anything was dispensed
+    async fn dose(
+        &mut self,
+        pumps: &mut [GuardedPump; PUMP_COUNT],
+        ph: PhState,
+        config: &Config,
+    ) -> bool {
+        let (pump, error, before, raising) = match ph {
+            PhState::High(v) => (PH_DOWN_PUMP, v - config.ph_target, v, false),
+            PhState::Low(v) => (PH_UP_PUMP, config.ph_target - v, v, true),
+            PhState::Good(_) | PhState::Unknown => return false,
+        };
+        let pump = &mut pumps[pump];
+
+        let remaining = self.daily.remaining(config.ph_max_daily_ml);
+        let Some(ml) = ph_dose_volume(error, config, remaining) else {
+            if remaining < MIN_DOSE_ML {
+                warn!("Daily pH dosing limit reached");
+            }
+            return false;
+        };
+
+        info!(
+            "Dosing {:.1}mL of {} (pH off by {:.2})",
+            ml, pump.name, error
+        );
+        let dispensed = match pump.dispense(ml, config).await {
+            Ok(dispensed) => dispensed,
+            Err(e @ (DoseError::TooSoon | DoseError::DailyLimit)) => {
+                warn!("Not dosing {}: {}", pump.name, e);
+                return false;
+            }
+            // The dose didn't go in, so there's nothing to count or check later
+            Err(e) => {
+                error!("{}: {}", pump.name, e);
+                return false;
+            }
+        };
+        self.daily.add(dispensed);
+        self.last_dose = Some((before, raising));
+        true
+    }
+}
+
+struct EcDoser {
+    daily: DailyTotal,
+    // EC before the last dose and how much it should have risen by
+    expected_rise: Option<(f32, f32)>,
+}
+
+impl EcDoser {
+    // Checks the last dose raised EC, returns false if dosing was locked out
+    async fn check_last_dose(&mut self, ec: EcState) -> bool {
+        let (EcState::Good(reading) | EcState::High(reading) | EcState::Low(reading)) = ec else {
+            return true;
+        };
+        // A dose that doesn't show up means an empty bottle, a blocked line or a bad probe
+        if let Some((before, rise)) = self.expected_rise.take()
+            && reading - before < rise * MIN_EC_RISE_FRACTION
+        {
+            error!(
+                "EC rose {:.0} after dosing, expected {:.0}. Locking out dosing",
+                reading - before,
+                rise
+            );
+            lock_out(DosingLockout::EcNoRise).await;
+            return false;
+        }
+        true
+    }
+
+    // Returns true if anything was dispensed
+    async fn dose(
+        &mut self,
+        pumps: &mut [GuardedPump; PUMP_COUNT],
+        ec: EcState,
+        litres: f32,
+        config: &Config,
+    ) -> bool {
+        let EcState::Low(reading) = ec else {
+            return false;
+        };
+
+        let parts = [
+            (PART_A_PUMP, config.nutrient_ratio_a),
+            (PART_B_PUMP, config.nutrient_ratio_b),
+            (PART_C_PUMP, config.nutrient_ratio_c),
+        ];
+        let ratio_total: f32 = parts.iter().map(|(_, ratio)| ratio).sum();
+        if ratio_total <= 0.0 {
+            warn!("No nutrient ratio configured");
+            return false;
+        }
+
+        let remaining = self.daily.remaining(config.ec_max_daily_ml);
+        let Some(ml) = ec_dose_volume(config.ec_target - reading, litres, config, remaining) else {
+            if remaining < MIN_DOSE_ML {
+                warn!("Daily EC dosing limit reached");
+            }
+            return false;
+        };
+
+        // Every part has to be allowed, a partial recipe would throw the ratio off
+        for (pump, ratio) in parts {
+            if ratio > 0.0 {
+                let pump = &mut pumps[pump];
+                if let Err(e) = pump.check(ml * ratio / ratio_total, config) {
+                    warn!("Not dosing nutrients, {}: {}", pump.name, e);
+                    return false;
+                }
+            }
+        }
+
+        info!("Dosing {:.1}mL of nutrients (EC {:.0})", ml, reading);
+        // Parts are dosed one at a time so the concentrates don't precipitate
+        let mut dosed_ml = 0.0;
+        let mut first = true;
+        for (pump, ratio) in parts {
+            if ratio <= 0.0 {
+                continue;
+            }
+            if !first {
+                Timer::after_secs(config.ec_part_mixing_secs as u64).await;
+            }
+            first = false;
+
+            let pump = &mut pumps[pump];
+            let part_ml = ml * ratio / ratio_total;
+            info!("Dosing {:.1}mL of {}", part_ml, pump.name);
+            match pump.dispense(part_ml, config).await {
+                Ok(dispensed) => dosed_ml += dispensed,
+                // The rest of the recipe would only throw the ratio off further. Finished parts
+                // are counted, but a partial recipe can't be checked against the EC rise.
+                Err(e) => {
+                    error!("{} failed mid-recipe, stopping: {}", pump.name, e);
+                    self.daily.add(dosed_ml);
+                    return dosed_ml > 0.0;
+                }
+            }
+        }
+        self.daily.add(dosed_ml);
+        self.expected_rise = Some((reading, dosed_ml * config.nutrient_ec_per_ml_per_l / litres));
+        true
+    }
+}
+
+/// Maintenance requests for the dosing pumps, `pump` indexes `Config::pump_calibration`
+#[derive(Debug, Clone, Copy)]
+pub enum PumpCommand {
+    /// Runs the pump for `secs` into a measuring cylinder
+    CalibrationRun { pump: usize, secs: u32 },
+    /// Sets the flow rate from what came out of the last calibration run
+    Calibrate { pump: usize, measured_ml: f32 },
+    /// Resets the dispensed volume after the bottle is refilled
+    Refilled { pump: usize },
+    /// Lets a pump run again after a current fault has been fixed
+    ClearFault { pump: usize },
+    /// Runs the pump for `secs` to prime or flush its line, maintenance mode only
+    Run { pump: usize, secs: u32 },
+    /// Dispenses `ml` within the usual pump limits, maintenance mode only
+    Dispense { pump: usize, ml: f32 },
+}
+
+impl PumpCommand {
+    // Whether the command runs a pump, which may have put something into the reservoir
+    fn runs_pump(self) -> bool {
+        matches!(
+            self,
+            PumpCommand::CalibrationRun { .. }
+                | PumpCommand::Run { .. }
+                | PumpCommand::Dispense { .. }
+        )
+    }
+}
+
+pub static PUMP_COMMANDS: Channel<CriticalSectionRawMutex, PumpCommand, 4> = Channel::new();
+
+/// Set whenever fresh water goes into the reservoir, which makes the checks on the last dose
+/// meaningless
+pub static WATER_ADDED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+async fn handle_command(
+    command: PumpCommand,
+    pumps: &mut [GuardedPump; PUMP_COUNT],
+    calibration_runs: &mut [Option<CalibrationRun>; PUMP_COUNT],
+    store: &SharedConfigStore,
+    config: &Config,
+) {
+    match command {
+        PumpCommand::CalibrationRun { pump, secs } => {
+            let Some(pump) = pumps.get_mut(pump) else {
+                return;
+            };
+            if secs > config.pump_max_run_secs {
+                warn!(
+                    "Calibration run of {}s is longer than the {}s limit",
+                    secs, config.pump_max_run_secs
+                );
+                return;
+            }
+            info!("Calibration run of {} for {}s", pump.name, secs);
+            match pump.pump.calibration_run(secs).await {
+                Ok(run) => calibration_runs[pump.index] = Some(run),
+                Err(e) => error!("Calibration run of {} failed: {}", pump.name, e),
+            }
+            pump.publish().await;
+        }
+        PumpCommand::Calibrate { pump, measured_ml } => {
+            let Some(pump) = pumps.get_mut(pump) else {
+                return;
+            };
+            let Some(run) = calibration_runs[pump.index].take() else {
+                warn!("{} needs a calibration run first", pump.name);
+                return;
+            };
+            let calibration = pump.pump.calibrate(run, measured_ml);
+            info!(
+                "{} calibrated to {:.2}mL/s at {}% duty",
+                pump.name, calibration.ml_per_sec, calibration.duty_percent
+            );
+            let index = pump.index;
+            if let Err(e) = config::update(store, |c| c.pump_calibration[index] = calibration).await
+            {
+                error!("Failed to save pump calibration: {}", e);
+            }
+        }
+        PumpCommand::Refilled { pump } => {
+            let Some(pump) = pumps.get_mut(pump) else {
+                return;
+            };
+            info!("{} refilled", pump.name);
+            pump.pump.reset_dispensed();
+            pump.publish().await;
+            save_dispensed(pumps, store, config).await;
+        }
+        PumpCommand::ClearFault { pump } => {
+            let Some(pump) = pumps.get_mut(pump) else {
+                return;
+            };
+            info!("{} fault cleared", pump.name);
+            pump.pump.clear_fault();
+            pump.publish().await;
+        }
+        PumpCommand::Run { pump, secs } => {
+            let Some(pump) = pumps.get_mut(pump) else {
+                return;
+            };
+            if !in_maintenance() {
+                warn!("{} can only be run by hand in maintenance mode", pump.name);
+                return;
+            }
+            if secs > config.pump_max_run_secs {
+                warn!(
+                    "Manual run of {}s is longer than the {}s limit",
+                    secs, config.pump_max_run_secs
+                );
+                return;
+            }
+            info!("Running {} by hand for {}s", pump.name, secs);
+            if let Err(e) = pump.pump.run_for(Duration::from_secs(secs as u64)).await {
+                error!("Manual run of {} failed: {}", pump.name, e);
+            }
+            pump.publish().await;
+        }
+        PumpCommand::Dispense { pump, ml } => {
+            let Some(pump) = pumps.get_mut(pump) else {
+                return;
+            };
+            if !in_maintenance() {
+                warn!("{} can only be run by hand in maintenance mode", pump.name);
+                return;
+            }
+            info!("Dispensing {:.1}mL of {} by hand", ml, pump.name);
+            if let Err(e) = pump.dispense(ml, config).await {
+                error!("Manual dose of {} failed: {}", pump.name, e);
+            }
+        }
+    }
+}
+
+// Keeps the bottle levels through a reset. Saving erases a flash sector, which stalls every task
+// while it runs, so it's only done every `SAVE_DISPENSED_INTERVAL` and after a refill. At most
+// one interval's dosing can be lost.
+async fn save_dispensed(
+    pumps: &[GuardedPump; PUMP_COUNT],
+    store: &SharedConfigStore,
+    config: &Config,
+) {
+    let dispensed = pumps.each_ref().map(|pump| pump.pump.dispensed_ml());
+    if dispensed == config.pump_dispensed_ml {
+        return;
+    }
+    if let Err(e) = config::update(store, |c| c.pump_dispensed_ml = dispensed).await {
+        error!("Failed to save the dispensed volumes: {}", e);
+    }
+}
+
+async fn lock_out(reason: DosingLockout) {
+    update_state(|state| state.dosing_lockout = Some(reason)).await;
+}
+
+/// Clears a dosing lockout once someone has checked the probes and pumps
+pub async fn acknowledge_lockout() {
+    if let Some(reason) = update_state(|state| state.dosing_lockout.take()).await {
+        warn!("Dosing lockout ({:?}) acknowledged", reason);
+    }
+}
+
+// Why dosing can't happen right now
+fn dosing_blocked(state: &HydroponicState, config: &Config) -> Option<&'static str> {
+    if state.emergency.is_some() {
+        return Some("emergency stop");
+    }
+    if in_maintenance() {
+        return Some("maintenance mode");
+    }
+    if state.dosing_lockout.is_some() {
+        return Some("dosing is locked out");
+    }
+    if !state.water_level.band.above_low() {
+        return Some("water level is too low");
+    }
+    if state.topping_up {
+        return Some("reservoir is being topped up");
+    }
+    let max_age = Duration::from_secs(config.sensor_max_age_secs as u64);
+    let stale = |updated: Option<Instant>| updated.is_none_or(|t| t.elapsed() > max_age);
+    if stale(state.ec_updated) || stale(state.ph_updated) {
+        return Some("sensor readings are stale");
+    }
+    None
+}
+
+// All dosing goes through this one task so pH and EC adjustments never overlap.
+// EC goes first, since adding nutrients shifts pH anyway.
+#[embassy_executor::task]
+pub async fn dosing_task(motors: [PumpMotor; PUMP_COUNT], store: &'static SharedConfigStore) {
+    let (calibrations, dispensed) = {
+        let config = CONFIG.lock().await;
+        (config.pump_calibration, config.pump_dispensed_ml)
+    };
+    let names = ["pH up", "pH down", "part A", "part B", "part C"];
+    let mut index = 0;
+    let mut pumps = motors.map(|motor| {
+        let pump = GuardedPump {
+            index,
+            name: names[index],
+            pump: DosingPump::new(Interlocked::new(motor), calibrations[index])
+                .with_dispensed(dispensed[index]),
+            daily: DailyTotal::new(),
+            last_run: None,
+        };
+        index += 1;
+        pump
+    });
+    // The restored totals are in the state before anything is dosed
+    for pump in &pumps {
+        pump.publish().await;
+    }
+    let mut ph_doser = PhDoser {
+        daily: DailyTotal::new(),
+        last_dose: None,
+    };
+    let mut ec_doser = EcDoser {
+        daily: DailyTotal::new(),
+        expected_rise: None,
+    };
+    let mut calibration_runs = [None; PUMP_COUNT];
+    // Readings older than this were taken before the last dose had mixed in
+    let mut ignore_before = Instant::from_ticks(0);
+    let mut last_blocked = None;
+    let mut last_saved = Instant::now();
+
+    loop {
+        let command = match select(
+            Timer::after_secs(POLL_INTERVAL_SECS),
+            PUMP_COMMANDS.receive(),
+        )
+        .await
+        {
+            Either::First(()) => None,
+            Either::Second(command) => Some(command),
+        };
+
+        let state = *MACHINE_STATE.lock().await;
+        let config = *CONFIG.lock().await;
+        // Picks up calibrations and thresholds changed through the config
+        for pump in pumps.iter_mut() {
+            pump.pump
+                .set_calibration(config.pump_calibration[pump.index]);
+            if let Some(current) = pump.pump.actuator_mut().inner_mut().current_sense_mut() {
+                current.set_thresholds(config.current_thresholds());
+            }
+        }
+
+        if last_saved.elapsed() >= SAVE_DISPENSED_INTERVAL {
+            save_dispensed(&pumps, store, &config).await;
+            last_saved = Instant::now();
+        }
+
+        // The last doses can only be judged if nothing else has changed the reservoir since
+        if WATER_ADDED.try_take().is_some() || command.is_some_and(PumpCommand::runs_pump) {
+            ph_doser.last_dose = None;
+            ec_doser.expected_rise = None;
+        }
+
+        if let Some(command) = command {
+            handle_command(command, &mut pumps, &mut calibration_runs, store, &config).await;
+            continue;
+        }
+
+        let blocked = dosing_blocked(&state, &config);
+        if blocked != last_blocked {
+            match blocked {
+                Some(reason) => warn!("Dosing paused: {}", reason),
+                None => info!("Dosing resumed"),
+            }
+            last_blocked = blocked;
+        }
+        if blocked.is_some() {
+            continue;
+        }
+
+        // Both sensors need a reading taken after the last dose settled
+        let settled = |updated: Option<Instant>| updated.is_some_and(|t| t >= ignore_before);
+        if !settled(state.ec_updated) || !settled(state.ph_updated) {
+            continue;
+        }
+
+        // Both checks see the readings before either doser changes anything
+        if !ec_doser.check_last_dose(state.ec).await || !ph_doser.check_last_dose(state.ph).await {
+            continue;
+        }
+
+        // A measured volume beats the configured one, since it drops between top-ups.
+        // Each dose also moves the other reading, so it voids the other doser's pending check.
+        let litres = state.water_level.litres.unwrap_or(config.reservoir_litres);
+        let mixing_secs = if ec_doser.dose(&mut pumps, state.ec, litres, &config).await {
+            ph_doser.last_dose = None;
+            config.ec_mixing_secs
+        } else if ph_doser.dose(&mut pumps, state.ph, &config).await {
+            ec_doser.expected_rise = None;
+            config.ph_mixing_secs
+        } else {
+            continue;
+        };
+
+        // Keeps handling commands while the dose mixes in
+        ignore_before =
+            Instant::now() + Duration::from_secs(mixing_secs.max(config.dose_settle_secs) as u64);
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum DoseError {
+    #[error("Pump ran too recently")]
+    TooSoon,
+    #[error("Daily pump limit reached")]
+    DailyLimit,
+    #[error("Failed to drive the pump")]
+    Hardware,
+    #[error("Pump fault: {0:?}")]
+    Fault(MotorFault),
+    #[error("Emergency stop")]
+    EmergencyStop,
+}
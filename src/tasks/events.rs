@@ -0,0 +1,171 @@
+// Changes to the machine state, for anything that wants to follow along as they happen
+// rather than poll, like the SSE and WebSocket streams
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    pubsub::{PubSubChannel, Subscriber},
+};
+use embassy_time::Duration;
+use serde::Serialize;
+
+use crate::{
+    config::PUMP_COUNT,
+    hardware::actuator::MotorFault,
+    leak::Emergency,
+    tasks::state::{DosingLockout, HydroponicState},
+};
+
+// Events kept for a subscriber that's behind. One that falls further back is told it lagged.
+const EVENT_CAPACITY: usize = 8;
+/// SSE and WebSocket subscriptions that can be open at once. Each one holds an HTTP worker
+/// for as long as it's open, so this leaves some free for ordinary requests.
+pub const MAX_EVENT_SUBSCRIBERS: usize = 2;
+// Everything goes through the immediate publisher, which doesn't take a slot
+const EVENT_PUBLISHERS: usize = 0;
+
+/// The data is in the JSON shape sent to clients. Readings only say which one changed, the
+/// value is in the machine state.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Event {
+    Ph,
+    Ec,
+    WaterLevel,
+    Emergency(Option<Emergency>),
+    DosingLockout(Option<DosingLockout>),
+    Pump {
+        pump: usize,
+        dispensed_ml: f32,
+        fault: Option<MotorFault>,
+    },
+    Light {
+        percent: u8,
+        overridden: bool,
+    },
+    Irrigation {
+        on: bool,
+    },
+    TopUp {
+        active: bool,
+        last_litres: f32,
+        timed_out: bool,
+    },
+    Maintenance {
+        secs_left: Option<u64>,
+    },
+}
+
+/// Every event type, as named by `Event::name`
+pub const EVENT_NAMES: [&str; 10] = [
+    "ph",
+    "ec",
+    "water_level",
+    "emergency",
+    "dosing_lockout",
+    "pump",
+    "light",
+    "irrigation",
+    "top_up",
+    "maintenance",
+];
+
+impl Event {
+    /// Used as the SSE event type
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Ph => "ph",
+            Event::Ec => "ec",
+            Event::WaterLevel => "water_level",
+            Event::Emergency(_) => "emergency",
+            Event::DosingLockout(_) => "dosing_lockout",
+            Event::Pump { .. } => "pump",
+            Event::Light { .. } => "light",
+            Event::Irrigation { .. } => "irrigation",
+            Event::TopUp { .. } => "top_up",
+            Event::Maintenance { .. } => "maintenance",
+        }
+    }
+}
+
+pub static EVENTS: PubSubChannel<
+    CriticalSectionRawMutex,
+    Event,
+    EVENT_CAPACITY,
+    MAX_EVENT_SUBSCRIBERS,
+    EVENT_PUBLISHERS,
+> = PubSubChannel::new();
+
+pub type EventSubscriber = Subscriber<
+    'static,
+    CriticalSectionRawMutex,
+    Event,
+    EVENT_CAPACITY,
+    MAX_EVENT_SUBSCRIBERS,
+    EVENT_PUBLISHERS,
+>;
+
+/// Never waits, a full channel drops its oldest event
+pub fn publish(event: Event) {
+    EVENTS.immediate_publisher().publish_immediate(event);
+}
+
+pub fn publish_maintenance(left: Option<Duration>) {
+    publish(Event::Maintenance {
+        secs_left: left.map(|d| d.as_secs()),
+    });
+}
+
+/// Publishes an event for each part of the state that differs
+pub fn publish_changes(before: &HydroponicState, after: &HydroponicState) {
+    // Every fresh reading is sent, even with the same value, as it's no longer stale
+    if before.ph != after.ph || before.ph_updated != after.ph_updated {
+        publish(Event::Ph);
+    }
+    if before.ec != after.ec || before.ec_updated != after.ec_updated {
+        publish(Event::Ec);
+    }
+    if before.water_level != after.water_level
+        || before.water_level_updated != after.water_level_updated
+    {
+        publish(Event::WaterLevel);
+    }
+    if before.emergency != after.emergency {
+        publish(Event::Emergency(after.emergency));
+    }
+    if before.dosing_lockout != after.dosing_lockout {
+        publish(Event::DosingLockout(after.dosing_lockout));
+    }
+    for pump in 0..PUMP_COUNT {
+        if before.pump_dispensed_ml[pump] != after.pump_dispensed_ml[pump]
+            || before.pump_faults[pump] != after.pump_faults[pump]
+        {
+            publish(Event::Pump {
+                pump,
+                dispensed_ml: after.pump_dispensed_ml[pump],
+                fault: after.pump_faults[pump],
+            });
+        }
+    }
+    if before.light_percent != after.light_percent
+        || before.light_overridden != after.light_overridden
+    {
+        publish(Event::Light {
+            percent: after.light_percent,
+            overridden: after.light_overridden,
+        });
+    }
+    if before.irrigation_pump_on != after.irrigation_pump_on {
+        publish(Event::Irrigation {
+            on: after.irrigation_pump_on,
+        });
+    }
+    if before.topping_up != after.topping_up
+        || before.last_top_up_litres != after.last_top_up_litres
+        || before.top_up_timed_out != after.top_up_timed_out
+    {
+        publish(Event::TopUp {
+            active: after.topping_up,
+            last_litres: after.last_top_up_litres,
+            timed_out: after.top_up_timed_out,
+        });
+    }
+}
@@ -0,0 +1,58 @@
+// Keeps the last day of readings for the dashboard charts
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embassy_time::Timer;
+use heapless::Deque;
+
+use crate::tasks::state::{EcState, HydroponicState, MACHINE_STATE, PhState};
+
+pub const HISTORY_LEN: usize = 48;
+pub const HISTORY_INTERVAL_SECS: u64 = 30 * 60;
+
+/// Readings are stored as integers so a day of them fits in one small JSON response
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sample {
+    // pH × 100
+    pub ph_centi: Option<u16>,
+    // uS/cm
+    pub ec: Option<u16>,
+    // Only known with a continuous level sensor
+    pub level_percent: Option<u8>,
+}
+
+impl Sample {
+    fn from_state(state: &HydroponicState) -> Sample {
+        let ph = match state.ph {
+            PhState::Good(v) | PhState::High(v) | PhState::Low(v) => Some(v),
+            PhState::Unknown => None,
+        };
+        let ec = match state.ec {
+            EcState::Good(v) | EcState::High(v) | EcState::Low(v) => Some(v),
+            EcState::Unknown => None,
+        };
+        // `as` saturates, so a wild reading can't wrap around
+        Sample {
+            ph_centi: ph.map(|v| (v * 100.0 + 0.5) as u16),
+            ec: ec.map(|v| (v + 0.5) as u16),
+            level_percent: state.water_level.percent.map(|v| (v + 0.5) as u8),
+        }
+    }
+}
+
+/// Oldest first
+pub static HISTORY: Mutex<CriticalSectionRawMutex, Deque<Sample, HISTORY_LEN>> =
+    Mutex::new(Deque::new());
+
+#[embassy_executor::task]
+pub async fn history_task() {
+    loop {
+        // The first readings take a while after boot, so the first sample waits too
+        Timer::after_secs(HISTORY_INTERVAL_SECS).await;
+
+        let sample = Sample::from_state(&*MACHINE_STATE.lock().await);
+        let mut history = HISTORY.lock().await;
+        if history.is_full() {
+            history.pop_front();
+        }
+        let _ = history.push_back(sample);
+    }
+}
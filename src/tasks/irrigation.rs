@@ -0,0 +1,153 @@
+// Runs the main pump on flood/drain (ebb and flow) or continuous (NFT) cycles
+use embassy_futures::select::{Either3, select3};
+use embassy_rp::gpio::Output;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+use log::*;
+
+use crate::{
+    clock,
+    config::{CONFIG, Config},
+    hardware::actuator::{Actuator, Interlocked, Relay, emergency_stopped},
+    lighting,
+    tasks::{
+        maintenance::{MAX_MAINTENANCE_MINS, in_maintenance},
+        state::{MACHINE_STATE, update_state},
+    },
+};
+
+pub type IrrigationPump = Interlocked<Relay<Output<'static>>>;
+
+const UPDATE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Runs the pump by hand, only acted on in maintenance mode
+#[derive(Debug, Clone, Copy)]
+pub enum IrrigationCommand {
+    Run { secs: u32 },
+    Stop,
+}
+
+pub static IRRIGATION_COMMANDS: Signal<CriticalSectionRawMutex, IrrigationCommand> = Signal::new();
+
+/// Longest run that can be asked for by hand. With the failsafe limit turned off, a run
+/// still can't outlast maintenance mode.
+pub fn max_manual_run_secs(config: &Config) -> u32 {
+    match config.irrigation_max_on_mins {
+        0 => MAX_MAINTENANCE_MINS * 60,
+        mins => mins * 60,
+    }
+}
+
+// The cycle to follow right now, in seconds
+fn current_cycle(config: &Config, local_secs: Option<u32>) -> (u32, u32) {
+    // Without a clock there's no night, so fall back to the day cycle
+    let day = local_secs.is_none_or(|secs| lighting::is_day(config, secs));
+    let (on, period) = if day {
+        (
+            config.irrigation_day_on_mins,
+            config.irrigation_day_period_mins,
+        )
+    } else {
+        (
+            config.irrigation_night_on_mins,
+            config.irrigation_night_period_mins,
+        )
+    };
+    (on * 60, period * 60)
+}
+
+// Whether the schedule wants the pump on. Cycles line up with local midnight when the
+// time is known, so they carry on in step after a reboot.
+fn scheduled_on(config: &Config, local_secs: Option<u32>) -> bool {
+    let (on, period) = current_cycle(config, local_secs);
+    if on == 0 || period == 0 {
+        return false;
+    }
+    if on >= period {
+        return true;
+    }
+    let now = local_secs.unwrap_or(Instant::now().as_secs() as u32);
+    now % period < on
+}
+
+#[embassy_executor::task]
+pub async fn irrigation_task(mut pump: IrrigationPump) {
+    // When the pump last switched on, for the max-on failsafe
+    let mut on_since: Option<Instant> = None;
+    // Set when the failsafe trips, cleared once the schedule turns the pump off
+    let mut tripped = false;
+    // End of a manual run
+    let mut manual_until: Option<Instant> = None;
+
+    loop {
+        let config = *CONFIG.lock().await;
+        let water_level = MACHINE_STATE.lock().await.water_level;
+        let scheduled = scheduled_on(&config, clock::local_secs_of_day(config.utc_offset_mins));
+
+        if !scheduled {
+            tripped = false;
+        }
+        let max_on = Duration::from_secs(config.irrigation_max_on_mins as u64 * 60);
+        if config.irrigation_max_on_mins > 0 && on_since.is_some_and(|t| t.elapsed() >= max_on) {
+            error!(
+                "Irrigation pump ran for over {} minutes, stopping it",
+                config.irrigation_max_on_mins
+            );
+            tripped = true;
+        }
+        // Never run the pump dry
+        let water_ok = water_level.band.above_low();
+        // The schedule is ignored in maintenance mode, the pump only runs when asked to
+        let maintenance = in_maintenance();
+        if !maintenance || manual_until.is_some_and(|t| Instant::now() >= t) {
+            manual_until = None;
+        }
+        let wanted = if maintenance {
+            manual_until.is_some()
+        } else {
+            scheduled && !tripped
+        };
+        let run = wanted && water_ok && !emergency_stopped();
+        if wanted && !water_ok && pump.is_on() {
+            warn!("Irrigation stopped, water level is {:?}", water_level.band);
+        }
+
+        if run != pump.is_on() {
+            info!("Irrigation pump {}", if run { "on" } else { "off" });
+            if let Err(e) = pump.set_on(run).await {
+                error!("Failed to switch the irrigation pump: {}", e);
+            }
+            on_since = run.then(Instant::now);
+        }
+        let on = pump.is_on();
+        update_state(|state| state.irrigation_pump_on = on).await;
+
+        // The interlock switches the pump off by itself the moment an emergency stop is raised
+        let next_update = Instant::now() + UPDATE_INTERVAL;
+        let wake = select3(
+            Timer::at(manual_until.map_or(next_update, |t| t.min(next_update))),
+            pump.wait_for_stop(),
+            IRRIGATION_COMMANDS.wait(),
+        )
+        .await;
+        match wake {
+            Either3::Third(IrrigationCommand::Run { secs }) if in_maintenance() => {
+                // The failsafe limit still applies to manual runs
+                let run_for = match config.irrigation_max_on_mins {
+                    0 => Duration::from_secs(secs as u64),
+                    _ => Duration::from_secs(secs as u64).min(max_on),
+                };
+                info!(
+                    "Running the irrigation pump by hand for {}s",
+                    run_for.as_secs()
+                );
+                manual_until = Some(Instant::now() + run_for);
+            }
+            Either3::Third(IrrigationCommand::Stop) => manual_until = None,
+            Either3::Third(IrrigationCommand::Run { .. }) => {
+                warn!("The irrigation pump can only be run by hand in maintenance mode");
+            }
+            Either3::First(()) | Either3::Second(()) => {}
+        }
+    }
+}
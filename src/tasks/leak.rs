@@ -0,0 +1,96 @@
+// Watches the floor leak probes and shuts everything down if one gets wet
+use embassy_futures::select::{select, select_array};
+use embassy_rp::gpio::Input;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{self, CONFIG, SharedConfigStore},
+    hardware::actuator::{clear_emergency_stop, emergency_stop},
+    tasks::state::update_state,
+};
+
+pub const MAX_LEAK_PROBES: usize = 4;
+
+// A probe has to stay wet this long, so a glitch doesn't shut the system down
+const CONFIRM_TIME: Duration = Duration::from_millis(200);
+// Re-checks the probes in case an edge was missed
+const RECHECK_SECS: u64 = 30;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Emergency {
+    // Index of the probe that tripped
+    Leak(u8),
+}
+
+static EMERGENCY_RESET: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Clears a latched emergency. If a probe is still wet it trips again straight away.
+pub fn reset_emergency() {
+    EMERGENCY_RESET.signal(());
+}
+
+// Probes are pulled up and shorted to ground by water
+fn wet_probe(probes: &[Option<Input<'static>>; MAX_LEAK_PROBES]) -> Option<usize> {
+    probes
+        .iter()
+        .position(|probe| probe.as_ref().is_some_and(|p| p.is_low()))
+}
+
+async fn wait_for_falling_edge(probe: &mut Option<Input<'static>>) {
+    match probe {
+        Some(probe) => probe.wait_for_falling_edge().await,
+        None => core::future::pending().await,
+    }
+}
+
+// Keeps the latch in flash, so it survives a panic or a power cut
+async fn save_latch(store: &SharedConfigStore, emergency: Option<Emergency>) {
+    if let Err(e) = config::update(store, |c| c.emergency = emergency).await {
+        error!("Failed to save the emergency stop: {}", e);
+    }
+}
+
+#[embassy_executor::task]
+pub async fn leak_task(
+    mut probes: [Option<Input<'static>>; MAX_LEAK_PROBES],
+    store: &'static SharedConfigStore,
+) {
+    // One from before a reset has already stopped everything at boot
+    let mut latched = CONFIG.lock().await.emergency;
+    loop {
+        if latched.is_none() && wet_probe(&probes).is_some() {
+            Timer::after(CONFIRM_TIME).await;
+            if let Some(probe) = wet_probe(&probes) {
+                emergency_stop();
+                error!(
+                    "LEAK DETECTED by probe {}, all pumps and valves stopped until reset",
+                    probe
+                );
+                let emergency = Emergency::Leak(probe as u8);
+                save_latch(store, Some(emergency)).await;
+                latched = Some(emergency);
+            }
+        }
+
+        if let Some(emergency) = latched {
+            update_state(|state| state.emergency = Some(emergency)).await;
+            EMERGENCY_RESET.reset();
+            EMERGENCY_RESET.wait().await;
+            save_latch(store, None).await;
+            latched = None;
+            update_state(|state| state.emergency = None).await;
+            clear_emergency_stop();
+            warn!("Emergency stop reset");
+            continue;
+        }
+
+        select(
+            select_array(probes.each_mut().map(wait_for_falling_edge)),
+            Timer::after_secs(RECHECK_SECS),
+        )
+        .await;
+    }
+}
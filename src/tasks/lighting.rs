@@ -0,0 +1,224 @@
+// Runs the grow light on a daily photoperiod, with sunrise/sunset dimming
+use embassy_futures::select::{Either, select};
+use embassy_rp::{gpio::Output, pwm::PwmOutput};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{Duration, Instant, Timer};
+use log::*;
+
+use crate::{
+    clock,
+    config::{self, CONFIG, Config, SharedConfigStore},
+    hardware::actuator::{Actuator, ActuatorError, PwmDimmer, Relay},
+    tasks::{maintenance::in_maintenance, state::update_state},
+};
+
+// Often enough for a smooth ramp, a 30 minute ramp moves about 1% per step
+const UPDATE_INTERVAL_SECS: u64 = 15;
+const SECS_PER_DAY: u32 = 86_400;
+
+/// Either kind of light the controller can drive. A relay is on for any level above 0.
+pub enum GrowLight {
+    #[allow(dead_code)] // The current board has a dimmable light
+    Relay(Relay<Output<'static>>),
+    Dimmer(PwmDimmer<PwmOutput<'static>>),
+}
+
+impl Actuator for GrowLight {
+    async fn set_level(&mut self, percent: u8) -> Result<(), ActuatorError> {
+        match self {
+            GrowLight::Relay(relay) => relay.set_level(percent).await,
+            GrowLight::Dimmer(dimmer) => dimmer.set_level(percent).await,
+        }
+    }
+
+    fn level(&self) -> u8 {
+        match self {
+            GrowLight::Relay(relay) => relay.level(),
+            GrowLight::Dimmer(dimmer) => dimmer.level(),
+        }
+    }
+}
+
+/// Only the fields that are `Some` are changed
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LightSchedule {
+    pub on_mins: Option<u32>,
+    pub photoperiod_mins: Option<u32>,
+    pub ramp_mins: Option<u32>,
+    pub max_percent: Option<u8>,
+    pub utc_offset_mins: Option<i32>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum LightCommand {
+    /// Holds the light at `percent` for `mins`, then goes back to the schedule
+    Override { percent: u8, mins: u32 },
+    /// Switches the light fully on if it's off and off if it's on, for `mins`
+    Toggle { mins: u32 },
+    /// Ends an override early
+    Resume,
+    /// Changes and saves the schedule
+    Schedule(LightSchedule),
+}
+
+pub static LIGHT_COMMANDS: Channel<CriticalSectionRawMutex, LightCommand, 4> = Channel::new();
+
+#[derive(Debug, Clone, Copy)]
+struct Override {
+    percent: u8,
+    until: Instant,
+}
+
+// Seconds since the light came on, if it is within the photoperiod
+fn since_lights_on(config: &Config, secs: u32) -> Option<u32> {
+    let period = (config.light_photoperiod_mins * 60).min(SECS_PER_DAY);
+    let on = (config.light_on_mins * 60) % SECS_PER_DAY;
+    let since_on = (secs + SECS_PER_DAY - on) % SECS_PER_DAY;
+    (since_on < period).then_some(since_on)
+}
+
+/// Whether `secs` after local midnight is in the light schedule's photoperiod
+pub fn is_day(config: &Config, secs: u32) -> bool {
+    since_lights_on(config, secs).is_some()
+}
+
+// Where the light should be `secs` after local midnight
+fn scheduled_percent(config: &Config, secs: u32) -> u8 {
+    let Some(since_on) = since_lights_on(config, secs) else {
+        return 0;
+    };
+    let period = (config.light_photoperiod_mins * 60).min(SECS_PER_DAY);
+
+    let max = config.light_max_percent.min(100) as u32;
+    // A light that is always on never sets
+    let ramp = if period == SECS_PER_DAY {
+        0
+    } else {
+        (config.light_ramp_mins * 60).min(period / 2)
+    };
+    if ramp == 0 {
+        return max as u8;
+    }
+    // Distance from the nearer of sunrise and sunset
+    let edge = since_on.min(period - since_on);
+    (max * edge.min(ramp) / ramp) as u8
+}
+
+// Remembers whether the light is meant to be on, for the next reset. Only written when it
+// changes, so about twice a day.
+async fn save_was_on(on: bool, store: &SharedConfigStore) {
+    if let Err(e) = config::update(store, |config| config.light_was_on = on).await {
+        error!("Failed to save the light state: {}", e);
+    }
+}
+
+async fn save_schedule(schedule: LightSchedule, store: &SharedConfigStore) {
+    let result = config::update(store, |config| {
+        if let Some(on) = schedule.on_mins {
+            config.light_on_mins = on;
+        }
+        if let Some(period) = schedule.photoperiod_mins {
+            config.light_photoperiod_mins = period;
+        }
+        if let Some(ramp) = schedule.ramp_mins {
+            config.light_ramp_mins = ramp;
+        }
+        if let Some(max) = schedule.max_percent {
+            config.light_max_percent = max;
+        }
+        if let Some(offset) = schedule.utc_offset_mins {
+            config.utc_offset_mins = offset;
+        }
+    })
+    .await;
+    if let Err(e) = result {
+        error!("Failed to save light schedule: {}", e);
+    }
+}
+
+#[embassy_executor::task]
+pub async fn lighting_task(mut light: GrowLight, store: &'static SharedConfigStore) {
+    let mut manual: Option<Override> = None;
+    let mut command = None;
+    let mut warned_no_clock = false;
+
+    loop {
+        match command.take() {
+            Some(LightCommand::Override { percent, mins }) => {
+                info!("Light held at {}% for {} minutes", percent, mins);
+                manual = Some(Override {
+                    percent: percent.min(100),
+                    until: Instant::now() + Duration::from_secs(mins as u64 * 60),
+                });
+            }
+            Some(LightCommand::Toggle { mins }) => {
+                let percent = if light.level() > 0 { 0 } else { 100 };
+                info!("Light toggled to {}% for {} minutes", percent, mins);
+                manual = Some(Override {
+                    percent,
+                    until: Instant::now() + Duration::from_secs(mins as u64 * 60),
+                });
+            }
+            Some(LightCommand::Resume) => manual = None,
+            Some(LightCommand::Schedule(schedule)) => save_schedule(schedule, store).await,
+            None => {}
+        }
+        if manual.is_some_and(|o| Instant::now() >= o.until) {
+            info!("Light override expired");
+            manual = None;
+        }
+
+        let config = *CONFIG.lock().await;
+        let secs = clock::local_secs_of_day(config.utc_offset_mins);
+        if let Some(day) = secs.map(|secs| is_day(&config, secs))
+            && day != config.light_was_on
+        {
+            save_was_on(day, store).await;
+        }
+        // The schedule is worked out from the time of day rather than the time since boot,
+        // so after a reboot the light picks up where it should be once the clock syncs
+        let target = match (manual, secs) {
+            (Some(o), _) => Some(o.percent),
+            // Stays as it is while someone works on the system
+            (None, _) if in_maintenance() => None,
+            (None, Some(secs)) => Some(scheduled_percent(&config, secs)),
+            // Until then, or if NTP can't be reached, it stays as the schedule last had it
+            (None, None) => {
+                if !warned_no_clock {
+                    warn!(
+                        "Clock not synced, keeping the light {} until it is",
+                        if config.light_was_on { "on" } else { "off" }
+                    );
+                    warned_no_clock = true;
+                }
+                Some(if config.light_was_on {
+                    config.light_max_percent.min(100)
+                } else {
+                    0
+                })
+            }
+        };
+
+        if let Some(target) = target.filter(|t| *t != light.level())
+            && let Err(e) = light.set_level(target).await
+        {
+            error!("Failed to set light level: {}", e);
+        }
+        let (percent, overridden) = (light.level(), manual.is_some());
+        update_state(|state| {
+            state.light_percent = percent;
+            state.light_overridden = overridden;
+        })
+        .await;
+
+        command = match select(
+            Timer::after_secs(UPDATE_INTERVAL_SECS),
+            LIGHT_COMMANDS.receive(),
+        )
+        .await
+        {
+            Either::First(()) => None,
+            Either::Second(command) => Some(command),
+        };
+    }
+}
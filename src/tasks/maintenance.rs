@@ -0,0 +1,59 @@
+// Maintenance mode suspends the automation while someone works on the system.
+// It times out by itself, so a forgotten session doesn't leave the plants unattended.
+use embassy_time::{Duration, Instant};
+use log::*;
+use portable_atomic::{AtomicU64, Ordering};
+
+use crate::tasks::events::publish_maintenance;
+
+/// Longest maintenance session that can be asked for
+pub const MAX_MAINTENANCE_MINS: u32 = 240;
+
+// Ticks at which maintenance mode ends, 0 when automation is running
+static MAINTENANCE_UNTIL: AtomicU64 = AtomicU64::new(0);
+
+/// Suspends automation for `mins`, or extends a session that's already running
+pub fn start_maintenance(mins: u32) {
+    let mins = mins.min(MAX_MAINTENANCE_MINS);
+    let until = Instant::now() + Duration::from_secs(mins as u64 * 60);
+    MAINTENANCE_UNTIL.store(until.as_ticks(), Ordering::Relaxed);
+    warn!(
+        "Maintenance mode for {} minutes, automation suspended",
+        mins
+    );
+    publish_maintenance(Some(until - Instant::now()));
+}
+
+/// Goes back to automatic control straight away
+pub fn end_maintenance() {
+    if MAINTENANCE_UNTIL.swap(0, Ordering::Relaxed) != 0 {
+        info!("Maintenance mode ended");
+        publish_maintenance(None);
+    }
+}
+
+/// Time left in maintenance mode, `None` while automation is running
+pub fn maintenance_remaining() -> Option<Duration> {
+    let until = MAINTENANCE_UNTIL.load(Ordering::Relaxed);
+    if until == 0 {
+        return None;
+    }
+    let until = Instant::from_ticks(until);
+    let now = Instant::now();
+    if now < until {
+        return Some(until - now);
+    }
+    // Only the first caller to see it expire logs it
+    if MAINTENANCE_UNTIL
+        .compare_exchange(until.as_ticks(), 0, Ordering::Relaxed, Ordering::Relaxed)
+        .is_ok()
+    {
+        warn!("Maintenance mode timed out, back to automatic control");
+        publish_maintenance(None);
+    }
+    None
+}
+
+pub fn in_maintenance() -> bool {
+    maintenance_remaining().is_some()
+}
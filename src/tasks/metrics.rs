@@ -0,0 +1,317 @@
+// Prometheus metrics for /metrics. Gauges are read from the machine state when scraped,
+// counters are kept here and bumped by whatever they count.
+use core::fmt::{self, Display, Write};
+
+use embassy_rp::adc::Channel;
+use embassy_time::{Instant, Timer};
+use portable_atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use crate::{
+    api::{Reading, WaterLevelReading},
+    config::{Config, PUMP_COUNT},
+    hardware::{current_sense::SharedAdc, level::LevelBand},
+    tasks::{maintenance::in_maintenance, networking::HTTP_WORKERS, state::HydroponicState},
+};
+
+const TEMPERATURE_INTERVAL_SECS: u64 = 30;
+
+// Label values, in the order of the `config::*_PUMP` indices
+const PUMP_LABELS: [&str; PUMP_COUNT] = ["ph_up", "ph_down", "part_a", "part_b", "part_c"];
+
+const LEVEL_BANDS: [(LevelBand, &str); 6] = [
+    (LevelBand::Unknown, "unknown"),
+    (LevelBand::BelowLow, "below_low"),
+    (LevelBand::Low, "low"),
+    (LevelBand::Normal, "normal"),
+    (LevelBand::High, "high"),
+    (LevelBand::Overflow, "overflow"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EzoSensor {
+    Ph,
+    Ec,
+}
+
+static EZO_ERRORS: [AtomicU32; 2] = [const { AtomicU32::new(0) }; 2];
+static DOSES: [AtomicU32; PUMP_COUNT] = [const { AtomicU32::new(0) }; PUMP_COUNT];
+// Whole µL, since there are no atomic floats
+static DOSED_MICROLITRES: [AtomicU64; PUMP_COUNT] = [const { AtomicU64::new(0) }; PUMP_COUNT];
+static WIFI_RECONNECTS: AtomicU32 = AtomicU32::new(0);
+static BUSY_HTTP_WORKERS: AtomicUsize = AtomicUsize::new(0);
+// Bits of an f32, NaN until the first reading
+static BOARD_TEMPERATURE: AtomicU32 = AtomicU32::new(f32::NAN.to_bits());
+
+pub fn count_ezo_error(sensor: EzoSensor) {
+    EZO_ERRORS[sensor as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a pump run and what it dispensed, even if it stopped early
+pub fn count_dose(pump: usize, ml: f32) {
+    DOSES[pump].fetch_add(1, Ordering::Relaxed);
+    DOSED_MICROLITRES[pump].fetch_add((ml.max(0.0) * 1000.0) as u64, Ordering::Relaxed);
+}
+
+pub fn count_wifi_reconnect() {
+    WIFI_RECONNECTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Tracks how many HTTP workers are holding a connection
+pub fn set_worker_busy(busy: bool) {
+    if busy {
+        BUSY_HTTP_WORKERS.fetch_add(1, Ordering::Relaxed);
+    } else {
+        BUSY_HTTP_WORKERS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Reads the RP2040's own temperature sensor. It's the board's temperature, not the water's,
+// but it shows an enclosure getting too hot.
+#[embassy_executor::task]
+pub async fn board_temperature_task(adc: &'static SharedAdc, mut sensor: Channel<'static>) {
+    loop {
+        if let Ok(counts) = adc.lock().await.read(&mut sensor).await {
+            // RP2040 datasheet 4.9.5: 0.706 V at 27 °C, falling 1.721 mV per degree
+            let volts = counts as f32 * 3.3 / 4096.0;
+            let celsius = 27.0 - (volts - 0.706) / 0.001721;
+            BOARD_TEMPERATURE.store(celsius.to_bits(), Ordering::Relaxed);
+        }
+        Timer::after_secs(TEMPERATURE_INTERVAL_SECS).await;
+    }
+}
+
+/// Writes every metric in the Prometheus text format
+pub fn render(out: &mut impl Write, state: &HydroponicState, config: &Config) -> fmt::Result {
+    let ph = Reading::ph(state, config);
+    let ec = Reading::ec(state, config);
+    let level = WaterLevelReading::new(state, config);
+
+    describe(out, "ph", "gauge", "Latest pH reading")?;
+    if let Some(v) = ph.value {
+        sample(out, "ph", &[], v)?;
+    }
+    describe(
+        out,
+        "ec_microsiemens",
+        "gauge",
+        "Latest EC reading in uS/cm",
+    )?;
+    if let Some(v) = ec.value {
+        sample(out, "ec_microsiemens", &[], v)?;
+    }
+    let readings = [
+        ("ph", ph.age_secs, ph.healthy),
+        ("ec", ec.age_secs, ec.healthy),
+        ("water_level", level.age_secs, level.healthy),
+    ];
+    describe(
+        out,
+        "reading_age_seconds",
+        "gauge",
+        "Time since the last reading",
+    )?;
+    for (sensor, age, _) in readings {
+        if let Some(age) = age {
+            sample(out, "reading_age_seconds", &[("sensor", sensor)], age)?;
+        }
+    }
+    describe(
+        out,
+        "reading_healthy",
+        "gauge",
+        "1 if the reading is recent enough to act on",
+    )?;
+    for (sensor, _, healthy) in readings {
+        sample(out, "reading_healthy", &[("sensor", sensor)], healthy as u8)?;
+    }
+    let temperature = f32::from_bits(BOARD_TEMPERATURE.load(Ordering::Relaxed));
+    describe(
+        out,
+        "board_temperature_celsius",
+        "gauge",
+        "Controller chip temperature",
+    )?;
+    if !temperature.is_nan() {
+        sample(out, "board_temperature_celsius", &[], temperature)?;
+    }
+
+    describe(
+        out,
+        "water_level_percent",
+        "gauge",
+        "Reservoir level, with a continuous sensor",
+    )?;
+    if let Some(v) = level.percent {
+        sample(out, "water_level_percent", &[], v)?;
+    }
+    describe(
+        out,
+        "water_level_litres",
+        "gauge",
+        "Water in the reservoir, with a continuous sensor",
+    )?;
+    if let Some(v) = level.litres {
+        sample(out, "water_level_litres", &[], v)?;
+    }
+    describe(
+        out,
+        "water_level_band",
+        "gauge",
+        "1 for the band the level is in",
+    )?;
+    for (band, name) in LEVEL_BANDS {
+        let current = state.water_level.band == band;
+        sample(out, "water_level_band", &[("band", name)], current as u8)?;
+    }
+
+    describe(out, "light_percent", "gauge", "Grow light brightness")?;
+    sample(out, "light_percent", &[], state.light_percent)?;
+    describe(
+        out,
+        "light_overridden",
+        "gauge",
+        "1 while the light is under manual control",
+    )?;
+    sample(out, "light_overridden", &[], state.light_overridden as u8)?;
+    describe(
+        out,
+        "irrigation_pump_on",
+        "gauge",
+        "1 while the irrigation pump runs",
+    )?;
+    sample(
+        out,
+        "irrigation_pump_on",
+        &[],
+        state.irrigation_pump_on as u8,
+    )?;
+    describe(
+        out,
+        "topping_up",
+        "gauge",
+        "1 while the reservoir is being topped up",
+    )?;
+    sample(out, "topping_up", &[], state.topping_up as u8)?;
+    describe(
+        out,
+        "top_up_timed_out",
+        "gauge",
+        "1 if the last top-up ran out of time",
+    )?;
+    sample(out, "top_up_timed_out", &[], state.top_up_timed_out as u8)?;
+    describe(
+        out,
+        "pump_dispensed_ml",
+        "gauge",
+        "Dispensed since the bottle was refilled",
+    )?;
+    for (pump, label) in PUMP_LABELS.into_iter().enumerate() {
+        let ml = state.pump_dispensed_ml[pump];
+        sample(out, "pump_dispensed_ml", &[("pump", label)], ml)?;
+    }
+    describe(
+        out,
+        "pump_fault",
+        "gauge",
+        "1 while a pump is stopped by a current fault",
+    )?;
+    for (pump, label) in PUMP_LABELS.into_iter().enumerate() {
+        let fault = state.pump_faults[pump].is_some();
+        sample(out, "pump_fault", &[("pump", label)], fault as u8)?;
+    }
+    describe(
+        out,
+        "emergency_stop",
+        "gauge",
+        "1 while a leak has everything stopped",
+    )?;
+    sample(out, "emergency_stop", &[], state.emergency.is_some() as u8)?;
+    describe(
+        out,
+        "dosing_locked_out",
+        "gauge",
+        "1 while dosing is locked out",
+    )?;
+    sample(
+        out,
+        "dosing_locked_out",
+        &[],
+        state.dosing_lockout.is_some() as u8,
+    )?;
+    describe(
+        out,
+        "maintenance_mode",
+        "gauge",
+        "1 while automation is suspended",
+    )?;
+    sample(out, "maintenance_mode", &[], in_maintenance() as u8)?;
+
+    describe(out, "doses_total", "counter", "Dosing pump runs since boot")?;
+    for (pump, label) in PUMP_LABELS.into_iter().enumerate() {
+        let doses = DOSES[pump].load(Ordering::Relaxed);
+        sample(out, "doses_total", &[("pump", label)], doses)?;
+    }
+    describe(out, "dosed_ml_total", "counter", "Dispensed since boot")?;
+    for (pump, label) in PUMP_LABELS.into_iter().enumerate() {
+        let ml = DOSED_MICROLITRES[pump].load(Ordering::Relaxed) as f64 / 1000.0;
+        sample(out, "dosed_ml_total", &[("pump", label)], ml)?;
+    }
+    describe(out, "ezo_errors_total", "counter", "Failed EZO board reads")?;
+    for (sensor, label) in [(EzoSensor::Ph, "ph"), (EzoSensor::Ec, "ec")] {
+        let errors = EZO_ERRORS[sensor as usize].load(Ordering::Relaxed);
+        sample(out, "ezo_errors_total", &[("sensor", label)], errors)?;
+    }
+    describe(
+        out,
+        "wifi_reconnects_total",
+        "counter",
+        "Rejoins after the Wi-Fi dropped",
+    )?;
+    sample(
+        out,
+        "wifi_reconnects_total",
+        &[],
+        WIFI_RECONNECTS.load(Ordering::Relaxed),
+    )?;
+    describe(out, "uptime_seconds_total", "counter", "Time since boot")?;
+    sample(out, "uptime_seconds_total", &[], Instant::now().as_secs())?;
+
+    // embassy-net allocates every socket up front and has no way to ask how many are in use.
+    // Each worker owns one TCP socket, so a free worker is a socket free for a new connection.
+    // The NTP and DNS sockets are always open, and nothing else in the stack is reported.
+    let busy = BUSY_HTTP_WORKERS.load(Ordering::Relaxed);
+    describe(
+        out,
+        "http_workers_free",
+        "gauge",
+        "HTTP workers, and so TCP sockets, free for a new connection",
+    )?;
+    sample(
+        out,
+        "http_workers_free",
+        &[],
+        HTTP_WORKERS.saturating_sub(busy),
+    )
+}
+
+fn describe(out: &mut impl Write, name: &str, kind: &str, help: &str) -> fmt::Result {
+    writeln!(out, "# HELP hydroponics_{} {}", name, help)?;
+    writeln!(out, "# TYPE hydroponics_{} {}", name, kind)
+}
+
+fn sample(
+    out: &mut impl Write,
+    name: &str,
+    labels: &[(&str, &str)],
+    value: impl Display,
+) -> fmt::Result {
+    write!(out, "hydroponics_{}", name)?;
+    for (i, (key, label)) in labels.iter().enumerate() {
+        let open = if i == 0 { '{' } else { ',' };
+        write!(out, "{}{}=\"{}\"", open, key, label)?;
+    }
+    if !labels.is_empty() {
+        out.write_char('}')?;
+    }
+    writeln!(out, " {}", value)
+}
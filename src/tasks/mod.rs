@@ -1,3 +1,12 @@
+pub mod clock;
 pub mod dose;
+pub mod events;
+pub mod history;
+pub mod irrigation;
+pub mod leak;
+pub mod lighting;
+pub mod maintenance;
+pub mod metrics;
 pub mod networking;
 pub mod state;
+pub mod top_up;
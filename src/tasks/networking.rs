@@ -1,25 +1,126 @@
-use core::str::from_utf8;
-
 use cyw43::{Control, JoinAuth, JoinOptions};
 use cyw43_pio::PioSpi;
 use embassy_executor::Spawner;
-use embassy_net::{Runner, Stack, tcp::TcpSocket};
+use embassy_futures::select::{Either, Either3, select, select3};
+use embassy_net::{
+    Runner, Stack,
+    tcp::{self, TcpSocket},
+};
 use embassy_rp::{
     gpio::Output,
     peripherals::{DMA_CH0, PIO0},
 };
-use embassy_time::Timer;
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex, pubsub::WaitResult,
+};
+use embassy_time::{Duration, TimeoutError, Timer, with_timeout};
 use embedded_io_async::Write;
 use heapless::{String, Vec};
 use log::*;
+use portable_atomic::{AtomicUsize, Ordering};
+use serde::{Serialize, de::DeserializeOwned};
 
-use core::fmt::Write as _;
+use core::{fmt::Write as _, str::from_utf8};
 
 use crate::{
     WIFI_PWD, WIFI_SSID,
-    state::{EcState, MACHINE_STATE, PhState, WaterLevelState},
+    api::{
+        ApiError, ConfigPatch, ConfigUpdated, DosingPatch, History, IntervalsPatch, MachineStatus,
+        NetworkPatch, Reading, SchedulesPatch, ThresholdsPatch, WaterLevelReading,
+    },
+    clock,
+    config::{self, CONFIG, ConfigError, PUMP_COUNT, SharedConfigStore},
+    dose::{self, PUMP_COMMANDS, PumpCommand},
+    events::{EVENT_NAMES, EVENTS, Event, EventSubscriber, MAX_EVENT_SUBSCRIBERS},
+    hardware::level::LevelBand,
+    history::HISTORY,
+    irrigation::{self, IRRIGATION_COMMANDS, IrrigationCommand},
+    leak,
+    lighting::{LIGHT_COMMANDS, LightCommand, LightSchedule},
+    maintenance::{MAX_MAINTENANCE_MINS, end_maintenance, in_maintenance, start_maintenance},
+    metrics,
+    state::{EcState, MACHINE_STATE, PhState},
+    top_up::{self, VALVE_COMMANDS, ValveCommand},
+};
+use hydroponic_automation_embassy::{
+    auth::{self, AuthError, Role},
+    http::{self, Method, ParseError, Request, Version, query_param},
+    websocket::{
+        self, ACCEPT_LEN, CLOSE_NORMAL, CLOSE_PROTOCOL_ERROR, CLOSE_TOO_BIG, CLOSE_UNSUPPORTED,
+        FrameError, Opcode,
+    },
 };
 
+const HTTP_PORT: u16 = 1234;
+/// Connections that can be served at once
+pub const HTTP_WORKERS: usize = 4;
+/// HTTP workers plus the NTP socket and the DNS socket embassy-net always adds. The address
+/// is static, so there's no DHCP socket.
+pub const SOCKET_COUNT: usize = HTTP_WORKERS + 2;
+// Event streams and WebSocket sessions hold their worker until the client goes away, so there
+// can't be more of them than leaves two workers for ordinary requests
+const MAX_LONG_LIVED: usize = MAX_EVENT_SUBSCRIBERS;
+const _: () = assert!(MAX_LONG_LIVED < HTTP_WORKERS - 1);
+// A connection with nothing sent or received for this long is closed
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+// Per worker, so kept small. Requests are only ever a few short headers and a JSON body.
+const RX_BUFFER_LEN: usize = 1024;
+const TX_BUFFER_LEN: usize = 1024;
+const REQUEST_BUFFER_LEN: usize = 2048;
+// Gzipped by build.rs from dashboard/index.html
+const DASHBOARD: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/dashboard.html.gz"));
+// Big enough for the whole state as JSON
+const RESPONSE_LEN: usize = 1024;
+// Room for the status line and headers of a JSON response
+const JSON_HEAD_LEN: usize = 96;
+// Room for the event type and framing around an event's JSON
+const EVENT_HEAD_LEN: usize = 32;
+// Sent on a quiet event stream or WebSocket so a client that has gone away is noticed
+const EVENT_KEEP_ALIVE: Duration = Duration::from_secs(15);
+// A WebSocket message with the whole state or a response in it, with room for escaping
+const WS_MESSAGE_LEN: usize = RESPONSE_LEN + 512;
+// Room for every metric in the Prometheus text format
+const METRICS_LEN: usize = 8192;
+
+static METRICS_BUFFER: Mutex<CriticalSectionRawMutex, String<METRICS_LEN>> =
+    Mutex::new(String::new());
+static LONG_LIVED: AtomicUsize = AtomicUsize::new(0);
+
+type Response = Vec<u8, RESPONSE_LEN>;
+type WsMessage = Vec<u8, WS_MESSAGE_LEN>;
+
+enum Reply {
+    Buffered(Response),
+    // Headers, then a page straight from flash
+    Page(Response, &'static [u8]),
+    // Rendered into the shared metrics buffer while it's sent
+    Metrics,
+    // The connection is handed over to the event stream
+    Events(LongLivedSlot),
+    // The connection is upgraded, with the accept key for the handshake
+    WebSocket(Role, String<ACCEPT_LEN>, LongLivedSlot),
+}
+
+// Held by an event stream or WebSocket session for as long as it's open
+struct LongLivedSlot;
+
+impl LongLivedSlot {
+    fn take() -> Option<LongLivedSlot> {
+        LONG_LIVED
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |open| {
+                (open < MAX_LONG_LIVED).then_some(open + 1)
+            })
+            .ok()
+            .map(|_| LongLivedSlot)
+    }
+}
+
+impl Drop for LongLivedSlot {
+    fn drop(&mut self) {
+        LONG_LIVED.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 type Cyw43Runner = cyw43::Runner<'static, Output<'static>, PioSpi<'static, PIO0, 0, DMA_CH0>>;
 
 #[embassy_executor::task]
@@ -28,11 +129,40 @@ pub async fn begin_hosting_task(
     net_runner: Runner<'static, cyw43::NetDriver<'static>>,
     mut control: Control<'static>,
     stack: Stack<'static>,
+    store: &'static SharedConfigStore,
 ) {
     // Begin network task
     spawner.spawn(net_task(net_runner)).unwrap();
 
     // Connect to wifi
+    join_wifi(&mut control).await;
+
+    info!("waiting for DHCP...");
+    while !stack.is_config_up() {
+        Timer::after_millis(100).await;
+    }
+    info!("DHCP is now up!");
+    spawner.spawn(clock::ntp_task(stack)).unwrap();
+
+    for id in 0..HTTP_WORKERS {
+        spawner.spawn(http_worker_task(id, stack, store)).unwrap();
+    }
+    control.gpio_set(0, false).await;
+
+    // Rejoins whenever the access point drops us, with the LED on until it's back
+    loop {
+        stack.wait_link_down().await;
+        warn!("Wi-Fi connection lost, rejoining");
+        control.gpio_set(0, true).await;
+        join_wifi(&mut control).await;
+        metrics::count_wifi_reconnect();
+        info!("Wi-Fi rejoined");
+        control.gpio_set(0, false).await;
+    }
+}
+
+// Keeps trying until it's joined
+async fn join_wifi(control: &mut Control<'static>) {
     loop {
         if let Some(pwd) = WIFI_PWD {
             let mut options = JoinOptions::default();
@@ -53,63 +183,540 @@ pub async fn begin_hosting_task(
             }
         }
     }
+}
 
-    info!("waiting for DHCP...");
-    while !stack.is_config_up() {
-        Timer::after_millis(100).await;
+// Each worker serves one connection at a time, so a slow client only holds up its own worker
+#[embassy_executor::task(pool_size = HTTP_WORKERS)]
+async fn http_worker_task(id: usize, stack: Stack<'static>, store: &'static SharedConfigStore) {
+    let mut rx_buffer = [0; RX_BUFFER_LEN];
+    let mut tx_buffer = [0; TX_BUFFER_LEN];
+    let mut buf = [0; REQUEST_BUFFER_LEN];
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        // Drops clients that stop acknowledging what's sent to them
+        socket.set_timeout(Some(IDLE_TIMEOUT));
+        if let Err(e) = socket.accept(HTTP_PORT).await {
+            warn!("accept error: {:?}", e);
+            continue;
+        }
+        info!(
+            "worker {} recieved connection from {:?}",
+            id,
+            socket.remote_endpoint()
+        );
+
+        metrics::set_worker_busy(true);
+        serve_connection(&mut socket, &mut buf, store).await;
+        socket.close();
+        let _ = socket.flush().await;
+        metrics::set_worker_busy(false);
     }
-    info!("DHCP is now up!");
+}
 
-    let mut rx_buffer = [0; 4096];
-    let mut tx_buffer = [0; 4096];
-    let mut buf = [0; 4096];
+// Answers requests until the client closes the connection, asks for it to be closed,
+// or sends something that can't be parsed
+async fn serve_connection(socket: &mut TcpSocket<'_>, buf: &mut [u8], store: &SharedConfigStore) {
+    // Bytes read so far, which can run into the next request
+    let mut len = 0;
+    loop {
+        // The request borrows the buffer, so it's gone by the time anything is read into it
+        let (reply, used, keep_alive) = {
+            let parsed = http::parse(&buf[..len], buf.len());
+            // A request can arrive over several reads. The socket timeout only covers
+            // unacknowledged data, so a client that goes quiet is dropped here.
+            if let Err(ParseError::Incomplete) = parsed {
+                drop(parsed);
+                match with_timeout(IDLE_TIMEOUT, socket.read(&mut buf[len..])).await {
+                    Ok(Ok(0)) | Err(TimeoutError) => return,
+                    Ok(Ok(n)) => {
+                        len += n;
+                        continue;
+                    }
+                    Ok(Err(e)) => {
+                        warn!("read error: {:?}", e);
+                        return;
+                    }
+                }
+            }
 
-    control.gpio_set(0, false).await;
+            match parsed {
+                Ok(req) => (respond(&req, store).await, req.len, req.keep_alive()),
+                // Where the next request would start is unknown, so the connection has to go
+                Err(e) => {
+                    warn!("Rejected request: {}", e);
+                    (Reply::Buffered(status_response(e.status())), len, false)
+                }
+            }
+        };
+
+        let written = match reply {
+            Reply::Buffered(response) => socket.write_all(&response).await,
+            Reply::Page(head, page) => write_page(socket, &head, page).await,
+            Reply::Metrics => serve_metrics(socket).await,
+            Reply::Events(_slot) => return stream_events(socket).await,
+            Reply::WebSocket(role, accept, _slot) => {
+                // Frames can follow straight after the upgrade request
+                buf.copy_within(used..len, 0);
+                return websocket_session(socket, buf, len - used, role, &accept, store).await;
+            }
+        };
+        if let Err(e) = written {
+            warn!("write error: {:?}", e);
+            return;
+        }
+        if !keep_alive {
+            return;
+        }
+        buf.copy_within(used..len, 0);
+        len -= used;
+    }
+}
+
+// Checks the credentials before handing the request on. Pages from flash are returned
+// separately from the headers, since they're too big to copy into a response.
+async fn respond(req: &Request<'_>, store: &SharedConfigStore) -> Reply {
+    let response = match auth::authorize(req) {
+        Ok(role) => {
+            info!("{:?} {} as {:?}", req.method, req.path, role);
+            match (req.method, req.path) {
+                (Method::Get, "/") => return Reply::Page(dashboard_head(), DASHBOARD),
+                (Method::Head, "/") => dashboard_head(),
+                (Method::Get, "/metrics") => return Reply::Metrics,
+                (Method::Get, "/api/events") => match LongLivedSlot::take() {
+                    Some(slot) => return Reply::Events(slot),
+                    None => too_many_long_lived(),
+                },
+                (Method::Get, "/api/ws") => match websocket::accept_key(req) {
+                    Some(accept) => match LongLivedSlot::take() {
+                        Some(slot) => return Reply::WebSocket(role, accept, slot),
+                        None => too_many_long_lived(),
+                    },
+                    None => text_response("400 Bad Request", "expected a WebSocket upgrade"),
+                },
+                _ => handle_request(req, store).await,
+            }
+        }
+        Err(AuthError::Unauthorized) => {
+            warn!("Unauthorized {:?} {}", req.method, req.path);
+            // Makes a browser ask for the token
+            Vec::from_slice(
+                b"HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Basic realm=\"hydroponics\"\r\nContent-Length: 0\r\n\r\n",
+            )
+            .unwrap()
+        }
+        Err(AuthError::Forbidden) => {
+            warn!("Read-only token used for {:?} {}", req.method, req.path);
+            text_response("403 Forbidden", "admin only")
+        }
+    };
+    Reply::Buffered(response)
+}
 
+async fn write_page(
+    socket: &mut TcpSocket<'_>,
+    head: &[u8],
+    page: &'static [u8],
+) -> Result<(), tcp::Error> {
+    socket.write_all(head).await?;
+    socket.write_all(page).await
+}
+
+// The text is too big to keep a buffer for in every worker, so they share one
+async fn serve_metrics(socket: &mut TcpSocket<'_>) -> Result<(), tcp::Error> {
+    let state = *MACHINE_STATE.lock().await;
+    let config = *CONFIG.lock().await;
+    let mut body = METRICS_BUFFER.lock().await;
+    body.clear();
+    if metrics::render(&mut *body, &state, &config).is_err() {
+        error!("Metrics don't fit in {} bytes", METRICS_LEN);
+        return socket
+            .write_all(&status_response("500 Internal Server Error"))
+            .await;
+    }
+    let mut head: String<128> = String::new();
+    core::write!(
+        &mut head,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n",
+        body.len()
+    )
+    .expect("BUFFER TOO SMALL!");
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(body.as_bytes()).await
+}
+
+// Sends the whole state, then every change to it as Server-Sent Events until the client
+// goes away. The connection holds its worker the whole time.
+async fn stream_events(socket: &mut TcpSocket<'_>) {
+    let Ok(mut events) = EVENTS.subscriber() else {
+        warn!("Too many event streams open");
+        let _ = socket
+            .write_all(&status_response("503 Service Unavailable"))
+            .await;
+        return;
+    };
+    let head =
+        b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\r\n";
+    if let Err(e) = socket.write_all(head).await {
+        warn!("write error: {:?}", e);
+        return;
+    }
+    let mut message = state_event().await;
     loop {
-        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
-        //socket.set_timeout(Some(Duration::from_secs(10)));
+        if let Err(e) = socket.write_all(&message).await {
+            warn!("event stream closed: {:?}", e);
+            return;
+        }
+        // Events are small and far apart, so don't leave them sitting in the buffer
+        if socket.flush().await.is_err() {
+            return;
+        }
+        message = match select(events.next_message(), Timer::after(EVENT_KEEP_ALIVE)).await {
+            Either::First(WaitResult::Message(event)) => {
+                sse_message(event.name(), &event_data(event).await)
+            }
+            // Some changes were missed, so start the client over with the whole state
+            Either::First(WaitResult::Lagged(missed)) => {
+                warn!("Event stream missed {} events", missed);
+                state_event().await
+            }
+            // A comment, which clients ignore
+            Either::Second(()) => Vec::from_slice(b": keep-alive\n\n").unwrap(),
+        };
+    }
+}
 
-        // info!("listening on TCP:1234");
-        // if let Err(e) = socket.accept(1234).await {
-        //     warn!("acception error: {:?}", e);
-        //     continue;
-        // }
-        let _ = socket.accept(1234).await;
+async fn state_event() -> Response {
+    let state = *MACHINE_STATE.lock().await;
+    let config = *CONFIG.lock().await;
+    sse_message("state", &MachineStatus::new(&state, &config))
+}
 
-        info!("recieved connection from {:?}", socket.remote_endpoint());
-        control.gpio_set(0, true).await;
+// What's sent for an event. Readings are in the same shape as their /api endpoint.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum EventData {
+    Reading(Reading),
+    WaterLevel(WaterLevelReading),
+    Change(Event),
+}
 
-        loop {
-            let n = match socket.read(&mut buf).await {
-                Ok(0) => {
-                    warn!("read EOF");
-                    break;
-                }
-                Ok(n) => n,
-                Err(e) => {
-                    warn!("read error: {:?}", e);
-                    break;
+async fn event_data(event: Event) -> EventData {
+    let state = *MACHINE_STATE.lock().await;
+    let config = *CONFIG.lock().await;
+    match event {
+        Event::Ph => EventData::Reading(Reading::ph(&state, &config)),
+        Event::Ec => EventData::Reading(Reading::ec(&state, &config)),
+        Event::WaterLevel => EventData::WaterLevel(WaterLevelReading::new(&state, &config)),
+        _ => EventData::Change(event),
+    }
+}
+
+fn sse_message<T: Serialize>(name: &str, value: &T) -> Response {
+    let mut data = [0; RESPONSE_LEN - EVENT_HEAD_LEN];
+    let len = match serde_json_core::to_slice(value, &mut data) {
+        Ok(len) => len,
+        Err(e) => {
+            error!("Failed to serialize {} event: {}", name, e);
+            return Vec::from_slice(b": dropped event\n\n").unwrap();
+        }
+    };
+    let mut message = Response::new();
+    let parts: [&[u8]; 5] = [
+        b"event: ",
+        name.as_bytes(),
+        b"\ndata: ",
+        &data[..len],
+        b"\n\n",
+    ];
+    for part in parts {
+        message.extend_from_slice(part).expect("BUFFER TOO SMALL!");
+    }
+    message
+}
+
+// Text messages from a WebSocket client, one per frame:
+//   SUBSCRIBE [<event> ...] => the whole state as a "state" event, then the named events as
+//       they happen, or all of them if none are named. Events are the ones /api/events sends.
+//   UNSUBSCRIBE => stops the events
+//   <METHOD> <path>[?<query>], then optionally a newline and a body
+//       => runs it like an HTTP request, with the same permissions as the upgrade request
+// Replies are JSON:
+//   {"event":"<name>","data":<same as /api/events>}
+//   {"status":<HTTP status code>,"body":"<response body>"}
+// Events, replies and pings share the connection, which holds its worker the whole time.
+async fn websocket_session(
+    socket: &mut TcpSocket<'_>,
+    buf: &mut [u8],
+    mut len: usize,
+    role: Role,
+    accept: &str,
+    store: &SharedConfigStore,
+) {
+    let mut head: String<160> = String::new();
+    core::write!(
+        &mut head,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept
+    )
+    .expect("BUFFER TOO SMALL!");
+    if let Err(e) = socket.write_all(head.as_bytes()).await {
+        warn!("write error: {:?}", e);
+        return;
+    }
+
+    let mut events = None;
+    // Bit i is set when events named EVENT_NAMES[i] are wanted
+    let mut wanted = 0;
+    let cap = buf.len();
+    loop {
+        let frame = match websocket::parse_frame(&mut buf[..len], cap) {
+            Ok(frame) => frame,
+            Err(FrameError::Incomplete) => {
+                let waited = select3(
+                    socket.read(&mut buf[len..]),
+                    next_event(&mut events),
+                    Timer::after(EVENT_KEEP_ALIVE),
+                )
+                .await;
+                let sent = match waited {
+                    Either3::First(Ok(0)) => return,
+                    Either3::First(Ok(n)) => {
+                        len += n;
+                        continue;
+                    }
+                    Either3::First(Err(e)) => {
+                        warn!("read error: {:?}", e);
+                        return;
+                    }
+                    Either3::Second(WaitResult::Message(event))
+                        if wanted & event_bit(&event) != 0 =>
+                    {
+                        let data = event_data(event).await;
+                        send_frame(socket, Opcode::Text, &ws_event(event.name(), &data)).await
+                    }
+                    Either3::Second(WaitResult::Message(_)) => continue,
+                    // Some changes were missed, so start the client over with the whole state
+                    Either3::Second(WaitResult::Lagged(missed)) => {
+                        warn!("WebSocket missed {} events", missed);
+                        send_frame(socket, Opcode::Text, &ws_state().await).await
+                    }
+                    Either3::Third(()) => send_frame(socket, Opcode::Ping, &[]).await,
+                };
+                if let Err(e) = sent {
+                    warn!("write error: {:?}", e);
+                    return;
                 }
-            };
+                continue;
+            }
+            Err(e) => {
+                warn!("Bad WebSocket frame: {}", e);
+                let _ = send_close(socket, e.close_code()).await;
+                return;
+            }
+        };
 
-            info!("rxd {}", from_utf8(&buf[..n]).unwrap());
+        let payload = &buf[frame.payload.clone()];
+        let sent = match frame.opcode {
+            Opcode::Text if frame.fin => {
+                let reply = ws_message(payload, role, &mut events, &mut wanted, store).await;
+                send_frame(socket, Opcode::Text, &reply).await
+            }
+            // A fragmented message, which means it's bigger than anything expected
+            Opcode::Text => {
+                let _ = send_close(socket, CLOSE_TOO_BIG).await;
+                return;
+            }
+            Opcode::Binary => {
+                let _ = send_close(socket, CLOSE_UNSUPPORTED).await;
+                return;
+            }
+            // Nothing was started that it could continue
+            Opcode::Continuation => {
+                let _ = send_close(socket, CLOSE_PROTOCOL_ERROR).await;
+                return;
+            }
+            Opcode::Ping => send_frame(socket, Opcode::Pong, payload).await,
+            Opcode::Pong => Ok(()),
+            Opcode::Close => {
+                let _ = send_close(socket, CLOSE_NORMAL).await;
+                return;
+            }
+        };
+        if let Err(e) = sent {
+            warn!("write error: {:?}", e);
+            return;
+        }
+        buf.copy_within(frame.len..len, 0);
+        len -= frame.len;
+    }
+}
 
-            let response = handle_request(&buf[..n]).await;
-            match socket.write_all(&response).await {
-                Ok(()) => {
-                    break;
+// Handles one text message, returning the reply
+async fn ws_message(
+    message: &[u8],
+    role: Role,
+    events: &mut Option<EventSubscriber>,
+    wanted: &mut u16,
+    store: &SharedConfigStore,
+) -> WsMessage {
+    let Ok(message) = from_utf8(message) else {
+        return ws_reply(400, "not UTF-8");
+    };
+    let (line, body) = message.split_once('\n').unwrap_or((message, ""));
+    let mut words = line.split_ascii_whitespace();
+    match words.next() {
+        Some("SUBSCRIBE") => {
+            let mut mask = 0;
+            for name in words {
+                match EVENT_NAMES.iter().position(|n| *n == name) {
+                    Some(i) => mask |= 1 << i,
+                    None => return ws_reply(400, "unknown event"),
                 }
-                Err(e) => {
-                    warn!("write error: {:?}", e);
-                    break;
+            }
+            if events.is_none() {
+                match EVENTS.subscriber() {
+                    Ok(subscriber) => *events = Some(subscriber),
+                    Err(_) => return ws_reply(503, "too many event streams"),
                 }
+            }
+            *wanted = if mask == 0 { u16::MAX } else { mask };
+            ws_state().await
+        }
+        Some("UNSUBSCRIBE") => {
+            *events = None;
+            *wanted = 0;
+            ws_reply(200, "unsubscribed")
+        }
+        Some(method) => {
+            let (Ok(method), Some(target), None) =
+                (Method::parse(method), words.next(), words.next())
+            else {
+                return ws_reply(400, "expected <METHOD> <path>");
             };
+            if auth::required_role(method) > role {
+                warn!("Read-only WebSocket sent {:?} {}", method, target);
+                return ws_reply(403, "admin only");
+            }
+            let (path, query) = target.split_once('?').unwrap_or((target, ""));
+            info!("WebSocket {:?} {}", method, path);
+            let req = Request {
+                method,
+                version: Version::Http11,
+                path,
+                query,
+                headers: Vec::new(),
+                body: body.as_bytes(),
+                len: message.len(),
+            };
+            let response = handle_request(&req, store).await;
+            let (status, body) = split_response(&response);
+            ws_reply(status, body)
         }
-        control.gpio_set(0, false).await;
+        None => ws_reply(400, "empty message"),
     }
 }
 
+// Waits forever when not subscribed
+async fn next_event(events: &mut Option<EventSubscriber>) -> WaitResult<Event> {
+    match events {
+        Some(events) => events.next_message().await,
+        None => core::future::pending().await,
+    }
+}
+
+fn event_bit(event: &Event) -> u16 {
+    EVENT_NAMES
+        .iter()
+        .position(|n| *n == event.name())
+        .map_or(0, |i| 1 << i)
+}
+
+async fn send_frame(
+    socket: &mut TcpSocket<'_>,
+    opcode: Opcode,
+    payload: &[u8],
+) -> Result<(), tcp::Error> {
+    let header = websocket::frame_header(opcode, payload.len());
+    socket.write_all(&header).await?;
+    socket.write_all(payload).await?;
+    // Messages are small and far apart, so don't leave them sitting in the buffer
+    socket.flush().await
+}
+
+async fn send_close(socket: &mut TcpSocket<'_>, code: u16) -> Result<(), tcp::Error> {
+    send_frame(socket, Opcode::Close, &code.to_be_bytes()).await
+}
+
+#[derive(Serialize)]
+struct WsEvent<'a, T> {
+    event: &'a str,
+    data: &'a T,
+}
+
+#[derive(Serialize)]
+struct WsReply<'a> {
+    status: u16,
+    body: &'a str,
+}
+
+async fn ws_state() -> WsMessage {
+    let state = *MACHINE_STATE.lock().await;
+    let config = *CONFIG.lock().await;
+    ws_event("state", &MachineStatus::new(&state, &config))
+}
+
+fn ws_event<T: Serialize>(name: &str, data: &T) -> WsMessage {
+    ws_json(&WsEvent { event: name, data })
+}
+
+fn ws_reply(status: u16, body: &str) -> WsMessage {
+    ws_json(&WsReply { status, body })
+}
+
+fn ws_json<T: Serialize>(value: &T) -> WsMessage {
+    let mut message = WsMessage::new();
+    message
+        .resize_default(WS_MESSAGE_LEN)
+        .expect("BUFFER TOO SMALL!");
+    match serde_json_core::to_slice(value, &mut message) {
+        Ok(len) => message.truncate(len),
+        Err(e) => {
+            error!("Failed to serialize WebSocket message: {}", e);
+            message.clear();
+            message
+                .extend_from_slice(br#"{"status":500,"body":"message too large"}"#)
+                .expect("BUFFER TOO SMALL!");
+        }
+    }
+    message
+}
+
+// Status code and body of a buffered response
+fn split_response(response: &[u8]) -> (u16, &str) {
+    let status = response
+        .get(9..12)
+        .and_then(|s| from_utf8(s).ok())
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(500);
+    let body = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map_or(&[][..], |i| &response[i + 4..]);
+    (status, from_utf8(body).unwrap_or_default())
+}
+
+fn dashboard_head() -> Response {
+    let mut resp: String<128> = String::new();
+    core::write!(
+        &mut resp,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n",
+        DASHBOARD.len()
+    )
+    .expect("BUFFER TOO SMALL!");
+    Vec::from_slice(resp.as_bytes()).expect("BUFFER TOO SMALL")
+}
+
 #[embassy_executor::task]
 pub async fn cyw43_task(runner: Cyw43Runner) -> ! {
     runner.run().await
@@ -121,116 +728,432 @@ async fn net_task(mut runner: embassy_net::Runner<'static, cyw43::NetDriver<'sta
 }
 
 // Accepts the request from the client and returns the appropriate response
-async fn handle_request(req: &[u8]) -> Vec<u8, 64> {
-    let mut sections = req.split(|b| b == &b' ');
-    let (method, path) = (
-        from_utf8(sections.next().unwrap()).unwrap(),
-        from_utf8(sections.next().unwrap()).unwrap(),
-    );
+async fn handle_request(req: &Request<'_>, store: &SharedConfigStore) -> Response {
+    let (method, path, query) = (req.method, req.path, req.query);
 
     // Possible paths:
-    // / => Hello World
+    // / => the dashboard, served before this is reached
     // /ph => (high/good/low), (ph value)
     // /ec => (high, good, low), (ec value)
-    // /waterlevel => (good/low)
-    // NOT IMPLEMENTED!!!
-    // /all => (high/good/low), (ph value), (high/good/low), (ec value), (good/low)
-    let good_status_line = "HTTP/1.1 200 OK\r\n";
+    // /waterlevel => (below low/low/normal/high/overflow), (percent), (litres)
+    // /pumps/<n> => dispensed, (mL since refill)
+    // POST /dosing/unlock => clears a dosing lockout
+    // POST /pumps/<n>/calibration-run?secs=<s> => runs pump n for s seconds
+    // POST /pumps/<n>/calibration?ml=<v> => v mL came out of the calibration run
+    // POST /pumps/<n>/refilled => resets the dispensed volume
+    // POST /pumps/<n>/clear-fault => lets a pump run again after a current fault
+    // /light => (percent), (schedule/override)
+    // POST /light/override?level=<pct>&mins=<m> => holds the light at a level for m minutes
+    // POST /light/toggle?mins=<m> => switches the light fully on or off for m minutes
+    // POST /light/resume => ends an override
+    // POST /light/schedule?on=<HH:MM>&hours=<h>&ramp=<mins>&max=<pct>&utc-offset=<mins>
+    //     => changes any of the given schedule settings
+    // POST /topup/reset => lets the top-up run again after a fill timed out
+    // POST /emergency/reset => clears a leak emergency stop
+    // POST /maintenance/start?mins=<m> => suspends automation for m minutes (60 by default)
+    // POST /maintenance/end => back to automatic control
+    // In maintenance mode only:
+    // POST /pumps/<n>/run?secs=<s> or ?ml=<v> => primes or doses by hand
+    // POST /irrigation/run?secs=<s>, /irrigation/stop => runs the main pump by hand, s <= max on
+    // POST /valve/open?secs=<s>, /valve/close => opens the fill valve by hand, s <= max fill
+    // /api/state => everything below as JSON, plus pumps, light, irrigation and top-up status
+    // /api/ph, /api/ec => JSON reading with its classification, timestamp and health
+    // /api/history => JSON arrays of the last day of pH × 100, EC and level %, oldest first
+    // /api/waterlevel => JSON band, percent and litres with the timestamp and health
+    // /api/events => Server-Sent Events, the state as a "state" event then one event per
+    //     change: ph, ec, water_level, emergency, dosing_lockout, pump, light, irrigation,
+    //     top_up, maintenance. Handled before this is reached.
+    // /metrics => Prometheus text format, handled before this is reached
+    // /api/ws => WebSocket for events and commands, see `websocket_session`. Handled before
+    //     this is reached.
+    // POST or PUT /api/config/<thresholds|intervals|dosing|schedules|network>
+    //     => JSON object with any of the section's fields, see `api::*Patch`.
+    //        Invalid values are rejected with 422 and nothing is changed.
     match method {
-        "GET" => {
-            match path {
-                "/" => {
-                    Vec::from_slice(b"HTTP/1.1 200 OK\r\nContent-Length: 13\r\n\r\nHello, world!")
-                        .unwrap()
+        Method::Get => match path {
+            "/ph" => {
+                info!("Hit ph path");
+                let (class, value) = match MACHINE_STATE.lock().await.ph {
+                    PhState::Good(v) => ("good", Some(v)),
+                    PhState::High(v) => ("high", Some(v)),
+                    PhState::Low(v) => ("low", Some(v)),
+                    PhState::Unknown => ("unk", None),
+                };
+                reading_response(class, value)
+            }
+            "/ec" => {
+                info!("Hit ec path");
+                let (class, value) = match MACHINE_STATE.lock().await.ec {
+                    EcState::Good(v) => ("good", Some(v)),
+                    EcState::High(v) => ("high", Some(v)),
+                    EcState::Low(v) => ("low", Some(v)),
+                    EcState::Unknown => ("unk", None),
+                };
+                reading_response(class, value)
+            }
+            "/waterlevel" => {
+                let level = MACHINE_STATE.lock().await.water_level;
+                let band = match level.band {
+                    LevelBand::Unknown => "unknown",
+                    LevelBand::BelowLow => "below low",
+                    LevelBand::Low => "low",
+                    LevelBand::Normal => "normal",
+                    LevelBand::High => "high",
+                    LevelBand::Overflow => "overflow",
+                };
+                let mut content: String<40> = String::new();
+                content.push_str(band).expect("BUFFER TOO SMALL!");
+                if let (Some(percent), Some(litres)) = (level.percent, level.litres) {
+                    core::write!(&mut content, ", {:.0}%, {:.1}L", percent, litres)
+                        .expect("BUFFER TOO SMALL!");
                 }
-                // FIXME: "Connection Reset"
-                "/ph" => {
-                    info!("Hit ph path");
-                    let mut resp: String<64> = String::new();
-                    resp.push_str(good_status_line).expect("BUFFER TOO SMALL!");
-
-                    let mut content: String<16> = String::new();
-                    // TODO: Get state
-                    let state = MACHINE_STATE.lock().await.ph;
-                    match state {
-                        PhState::Good(v) => {
-                            core::write!(&mut content, "good, {:.2}", v).expect("BUFFER TOO SMALL");
-                        }
-                        PhState::High(v) => {
-                            core::write!(&mut content, "high, {:.2}", v)
-                                .expect("BUFFER TOO SMALL!");
-                        }
-                        PhState::Low(v) => {
-                            core::write!(&mut content, "low, {:.2}", v).expect("BUFFER TOO SMALL!");
-                        }
-                        PhState::Unknown => {
-                            content.push_str("unk").expect("BUFFER TOO SMALL");
-                        }
-                    }
-                    core::write!(
-                        &mut resp,
-                        "Content-Length: {}\r\n\r\n{}",
-                        content.len(),
-                        content
-                    )
+                text_response("200 OK", &content)
+            }
+            p if p.starts_with("/pumps/") => {
+                let Some((pump, "")) = parse_pump_path(p) else {
+                    return not_found();
+                };
+                let dispensed = MACHINE_STATE.lock().await.pump_dispensed_ml[pump];
+                let mut content: String<24> = String::new();
+                core::write!(&mut content, "dispensed, {:.1}", dispensed)
+                    .expect("BUFFER TOO SMALL!");
+                text_response("200 OK", &content)
+            }
+            "/light" => {
+                let state = *MACHINE_STATE.lock().await;
+                let mode = if state.light_overridden {
+                    "override"
+                } else {
+                    "schedule"
+                };
+                let mut content: String<24> = String::new();
+                core::write!(&mut content, "{}, {}", state.light_percent, mode)
                     .expect("BUFFER TOO SMALL!");
-                    Vec::from_slice(resp.as_bytes()).expect("BUFFER TOO SMALL")
-                    //Vec::from_slice(b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\npH endpoint").unwrap()
+                text_response("200 OK", &content)
+            }
+            "/api/state" => {
+                let state = *MACHINE_STATE.lock().await;
+                let config = *CONFIG.lock().await;
+                json_response("200 OK", &MachineStatus::new(&state, &config))
+            }
+            "/api/ph" => {
+                let state = *MACHINE_STATE.lock().await;
+                let config = *CONFIG.lock().await;
+                json_response("200 OK", &Reading::ph(&state, &config))
+            }
+            "/api/ec" => {
+                let state = *MACHINE_STATE.lock().await;
+                let config = *CONFIG.lock().await;
+                json_response("200 OK", &Reading::ec(&state, &config))
+            }
+            "/api/history" => {
+                let history = History::new(&*HISTORY.lock().await);
+                json_response("200 OK", &history)
+            }
+            "/api/waterlevel" => {
+                let state = *MACHINE_STATE.lock().await;
+                let config = *CONFIG.lock().await;
+                json_response("200 OK", &WaterLevelReading::new(&state, &config))
+            }
+            _ => not_found(),
+        },
+        Method::Post | Method::Put if path.starts_with("/api/config/") => match path {
+            "/api/config/thresholds" => update_config::<ThresholdsPatch>(req.body, store).await,
+            "/api/config/intervals" => update_config::<IntervalsPatch>(req.body, store).await,
+            "/api/config/dosing" => update_config::<DosingPatch>(req.body, store).await,
+            "/api/config/schedules" => update_config::<SchedulesPatch>(req.body, store).await,
+            "/api/config/network" => update_config::<NetworkPatch>(req.body, store).await,
+            _ => not_found(),
+        },
+        Method::Post => match path {
+            "/dosing/unlock" => {
+                dose::acknowledge_lockout().await;
+                Vec::from_slice(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").unwrap()
+            }
+            p if p.starts_with("/pumps/") => {
+                let Some((pump, action)) = parse_pump_path(p) else {
+                    return not_found();
+                };
+                let command = match action {
+                    "/calibration-run" => query_param(query, "secs")
+                        .and_then(|v| v.parse::<u32>().ok())
+                        .filter(|secs| *secs > 0)
+                        .map(|secs| PumpCommand::CalibrationRun { pump, secs }),
+                    "/calibration" => query_param(query, "ml")
+                        .and_then(|v| v.parse::<f32>().ok())
+                        .filter(|ml| *ml > 0.0)
+                        .map(|measured_ml| PumpCommand::Calibrate { pump, measured_ml }),
+                    "/refilled" => Some(PumpCommand::Refilled { pump }),
+                    "/clear-fault" => Some(PumpCommand::ClearFault { pump }),
+                    "/run" if !in_maintenance() => return not_in_maintenance(),
+                    "/run" => match (query_param(query, "secs"), query_param(query, "ml")) {
+                        (Some(secs), None) => secs
+                            .parse::<u32>()
+                            .ok()
+                            .filter(|secs| *secs > 0)
+                            .map(|secs| PumpCommand::Run { pump, secs }),
+                        (None, Some(ml)) => ml
+                            .parse::<f32>()
+                            .ok()
+                            .filter(|ml| *ml > 0.0)
+                            .map(|ml| PumpCommand::Dispense { pump, ml }),
+                        _ => None,
+                    },
+                    _ => return not_found(),
+                };
+                let Some(command) = command else {
+                    return text_response("400 Bad Request", "bad parameter");
+                };
+                match PUMP_COMMANDS.try_send(command) {
+                    Ok(()) => text_response("202 Accepted", "queued"),
+                    Err(_) => text_response("503 Service Unavailable", "busy"),
                 }
-                "/ec" => {
-                    info!("Hit ec path");
-                    let mut resp: String<47> = String::new();
-                    resp.push_str(good_status_line).expect("BUFFER TOO SMALL!");
-
-                    let mut content: String<10> = String::new();
-                    // TODO: Get state
-                    let state = MACHINE_STATE.lock().await.ec;
-                    match state {
-                        EcState::Good(v) => {
-                            core::write!(&mut content, "good, {:.2}", v)
-                                .expect("BUFFER TOO SMALL!");
-                        }
-                        EcState::High(v) => {
-                            core::write!(&mut content, "high, {:.2}", v)
-                                .expect("BUFFER TOO SMALL!");
-                        }
-                        EcState::Low(v) => {
-                            core::write!(&mut content, "low, {:.2}", v).expect("BUFFER TOO SMALL!");
-                        }
-                        EcState::Unknown => content.push_str("unk").expect("BUFFER TOO SMALL"),
+            }
+            "/emergency/reset" => {
+                leak::reset_emergency();
+                text_response("200 OK", "ok")
+            }
+            "/topup/reset" => {
+                top_up::reset_top_up();
+                text_response("200 OK", "ok")
+            }
+            "/maintenance/start" => {
+                let mins = match query_param(query, "mins") {
+                    Some(v) => v.parse::<u32>().ok(),
+                    None => Some(60),
+                };
+                match mins.filter(|mins| (1..=MAX_MAINTENANCE_MINS).contains(mins)) {
+                    Some(mins) => {
+                        start_maintenance(mins);
+                        text_response("200 OK", "ok")
                     }
-                    core::write!(
-                        &mut resp,
-                        "Content-Length: {}\r\n\r\n{}",
-                        content.len(),
-                        content
-                    )
-                    .expect("BUFFER TOO SMALL!");
-                    Vec::from_slice(resp.as_bytes()).expect("BUFFER TOO SMALL")
-                    //Vec::from_slice(b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\nnec endpoint").unwrap()
+                    None => text_response("400 Bad Request", "bad parameter"),
+                }
+            }
+            "/maintenance/end" => {
+                end_maintenance();
+                text_response("200 OK", "ok")
+            }
+            "/irrigation/run" | "/irrigation/stop" | "/valve/open" | "/valve/close"
+                if !in_maintenance() =>
+            {
+                not_in_maintenance()
+            }
+            "/irrigation/run" => {
+                let max_secs = irrigation::max_manual_run_secs(&*CONFIG.lock().await);
+                let secs = query_param(query, "secs").and_then(|v| v.parse::<u32>().ok());
+                match secs.filter(|secs| (1..=max_secs).contains(secs)) {
+                    Some(secs) => {
+                        IRRIGATION_COMMANDS.signal(IrrigationCommand::Run { secs });
+                        text_response("202 Accepted", "queued")
+                    }
+                    None => text_response("400 Bad Request", "bad parameter"),
                 }
-                "/waterlevel" => {
-                    // TODO: Get state
-                    let state = MACHINE_STATE.lock().await.water_level;
-                    match state {
-                        WaterLevelState::Good => {
-                            Vec::from_slice(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\ngood")
-                                .unwrap()
-                        }
-                        WaterLevelState::Low => {
-                            Vec::from_slice(b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nlow")
-                                .unwrap()
-                        }
-                        WaterLevelState::Unknown => {
-                            Vec::from_slice(b"HTTP/1.1 200 OK\r\nContent-Length: 7\r\n\r\nunknown")
-                                .unwrap()
-                        }
+            }
+            "/irrigation/stop" => {
+                IRRIGATION_COMMANDS.signal(IrrigationCommand::Stop);
+                text_response("202 Accepted", "queued")
+            }
+            "/valve/open" => {
+                let max_secs = CONFIG.lock().await.top_up_max_fill_secs;
+                let secs = query_param(query, "secs").and_then(|v| v.parse::<u32>().ok());
+                match secs.filter(|secs| (1..=max_secs).contains(secs)) {
+                    Some(secs) => {
+                        VALVE_COMMANDS.signal(ValveCommand::Open { secs });
+                        text_response("202 Accepted", "queued")
                     }
+                    None => text_response("400 Bad Request", "bad parameter"),
                 }
-                _ => Vec::from_slice(b"HTTP/1.1 404 NOT FOUND\r\n").unwrap(),
             }
+            "/valve/close" => {
+                VALVE_COMMANDS.signal(ValveCommand::Close);
+                text_response("202 Accepted", "queued")
+            }
+            p if p.starts_with("/light/") => {
+                let command = match p {
+                    "/light/override" => {
+                        let percent =
+                            query_param(query, "level").and_then(|v| v.parse::<u8>().ok());
+                        let mins = match query_param(query, "mins") {
+                            Some(v) => v.parse::<u32>().ok(),
+                            None => Some(60),
+                        };
+                        percent
+                            .zip(mins)
+                            .filter(|(percent, _)| *percent <= 100)
+                            .map(|(percent, mins)| LightCommand::Override { percent, mins })
+                    }
+                    "/light/toggle" => match query_param(query, "mins") {
+                        Some(v) => v.parse::<u32>().ok(),
+                        None => Some(60),
+                    }
+                    .map(|mins| LightCommand::Toggle { mins }),
+                    "/light/resume" => Some(LightCommand::Resume),
+                    "/light/schedule" => parse_light_schedule(query).map(LightCommand::Schedule),
+                    _ => return not_found(),
+                };
+                let Some(command) = command else {
+                    return text_response("400 Bad Request", "bad parameter");
+                };
+                match LIGHT_COMMANDS.try_send(command) {
+                    Ok(()) => text_response("202 Accepted", "queued"),
+                    Err(_) => text_response("503 Service Unavailable", "busy"),
+                }
+            }
+            _ => not_found(),
+        },
+        Method::Head => status_response("200 OK"),
+        _ => status_response("501 Not Implemented"),
+    }
+}
+
+fn text_response(status: &str, content: &str) -> Response {
+    let mut resp: String<128> = String::new();
+    core::write!(
+        &mut resp,
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n{}",
+        status,
+        content.len(),
+        content
+    )
+    .expect("BUFFER TOO SMALL!");
+    Vec::from_slice(resp.as_bytes()).expect("BUFFER TOO SMALL")
+}
+
+// "<class>, <value>", the plain text format of /ph and /ec
+fn reading_response(class: &str, value: Option<f32>) -> Response {
+    // Room for any reading a probe could report, a wild value gets a 500 instead of a panic
+    let mut content: String<24> = String::new();
+    let written = match value {
+        Some(v) => core::write!(&mut content, "{}, {:.2}", class, v),
+        None => content.push_str(class).map_err(|_| core::fmt::Error),
+    };
+    if written.is_err() {
+        error!("Reading too long to report: {:?}", value);
+        return status_response("500 Internal Server Error");
+    }
+    text_response("200 OK", &content)
+}
+
+fn json_response<T: Serialize>(status: &str, value: &T) -> Response {
+    let mut body = [0; RESPONSE_LEN - JSON_HEAD_LEN];
+    let len = match serde_json_core::to_slice(value, &mut body) {
+        Ok(len) => len,
+        Err(e) => {
+            error!("Failed to serialize response: {}", e);
+            return status_response("500 Internal Server Error");
+        }
+    };
+    let mut head: String<JSON_HEAD_LEN> = String::new();
+    core::write!(
+        &mut head,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+        status,
+        len
+    )
+    .expect("BUFFER TOO SMALL!");
+    let mut resp = Response::new();
+    resp.extend_from_slice(head.as_bytes())
+        .expect("BUFFER TOO SMALL!");
+    resp.extend_from_slice(&body[..len])
+        .expect("BUFFER TOO SMALL!");
+    resp
+}
+
+// Changes one section of the config from the JSON in the body
+async fn update_config<P>(body: &[u8], store: &SharedConfigStore) -> Response
+where
+    P: ConfigPatch + DeserializeOwned,
+{
+    let patch = match serde_json_core::from_slice::<P>(body) {
+        Ok((patch, _)) => patch,
+        Err(e) => {
+            let mut error: String<96> = String::new();
+            core::write!(&mut error, "{}", e).expect("BUFFER TOO SMALL!");
+            return json_response("400 Bad Request", &ApiError { error: &error });
+        }
+    };
+    match config::update(store, |config| patch.apply(config)).await {
+        Ok(_) => json_response(
+            "200 OK",
+            &ConfigUpdated {
+                reboot_required: P::REBOOT_REQUIRED,
+            },
+        ),
+        Err(ConfigError::Invalid(reason)) => {
+            json_response("422 Unprocessable Content", &ApiError { error: reason })
         }
-        "HEAD" => Vec::from_slice(b"HTTP/1.1 200 OK\r\n").unwrap(),
-        _ => Vec::from_slice(b"HTTP/1.1 501 Not Implemented\r\n").unwrap(),
+        Err(e) => {
+            error!("Failed to save config: {}", e);
+            json_response(
+                "500 Internal Server Error",
+                &ApiError {
+                    error: "failed to save the config",
+                },
+            )
+        }
+    }
+}
+
+fn too_many_long_lived() -> Response {
+    warn!("Too many event streams and WebSockets open");
+    text_response("503 Service Unavailable", "too many event streams")
+}
+
+fn not_found() -> Response {
+    text_response("404 Not Found", "not found")
+}
+
+// Manual control is refused unless automation has been suspended first
+fn not_in_maintenance() -> Response {
+    text_response("409 Conflict", "not in maintenance mode")
+}
+
+// Empty response, for statuses too long to repeat in the body
+fn status_response(status: &str) -> Response {
+    let mut resp: String<96> = String::new();
+    core::write!(
+        &mut resp,
+        "HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n",
+        status
+    )
+    .expect("BUFFER TOO SMALL!");
+    Vec::from_slice(resp.as_bytes()).expect("BUFFER TOO SMALL")
+}
+
+// Splits "/pumps/<n><rest>" into a valid pump index and the rest of the path
+fn parse_pump_path(path: &str) -> Option<(usize, &str)> {
+    let rest = path.strip_prefix("/pumps/")?;
+    let end = rest.find('/').unwrap_or(rest.len());
+    let pump = rest[..end]
+        .parse::<usize>()
+        .ok()
+        .filter(|n| *n < PUMP_COUNT)?;
+    Some((pump, &rest[end..]))
+}
+
+// Every parameter is optional, but any that is given has to be valid
+fn parse_light_schedule(query: &str) -> Option<LightSchedule> {
+    let mut schedule = LightSchedule::default();
+    if let Some(on) = query_param(query, "on") {
+        let (hours, mins) = on.split_once(':')?;
+        let hours = hours.parse::<u32>().ok().filter(|h| *h < 24)?;
+        let mins = mins.parse::<u32>().ok().filter(|m| *m < 60)?;
+        schedule.on_mins = Some(hours * 60 + mins);
+    }
+    if let Some(hours) = query_param(query, "hours") {
+        let hours = hours.parse::<u32>().ok().filter(|h| *h <= 24)?;
+        schedule.photoperiod_mins = Some(hours * 60);
+    }
+    if let Some(ramp) = query_param(query, "ramp") {
+        schedule.ramp_mins = Some(ramp.parse::<u32>().ok()?);
+    }
+    if let Some(max) = query_param(query, "max") {
+        schedule.max_percent = Some(max.parse::<u8>().ok().filter(|m| *m <= 100)?);
+    }
+    if let Some(offset) = query_param(query, "utc-offset") {
+        let offset = offset.parse::<i32>().ok().filter(|o| o.abs() <= 14 * 60)?;
+        schedule.utc_offset_mins = Some(offset);
     }
+    Some(schedule)
 }
@@ -1,24 +1,60 @@
 use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
+use embassy_futures::select::{Either3, select, select3};
 use embassy_rp::{
-    gpio::Input,
     i2c::{Async, I2c},
     peripherals::I2C1,
 };
 use embassy_sync::{
     blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex},
     mutex::Mutex,
+    signal::Signal,
 };
-use embassy_time::Timer;
-use log::info;
+use embassy_time::{Duration, Instant, Timer};
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
 
-use crate::hardware::ezo::{EzoBoard, EzoCommand};
+use crate::{
+    config::{CONFIG, PUMP_COUNT},
+    hardware::{
+        actuator::MotorFault,
+        ezo::{EzoBoard, EzoCommand},
+        level::{ContinuousLevel, FloatSwitches, WaterLevel},
+    },
+    leak::Emergency,
+    tasks::{
+        events,
+        metrics::{self, EzoSensor},
+    },
+};
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
 pub struct HydroponicState {
     pub ec: EcState,
     pub ph: PhState,
-    pub water_level: WaterLevelState,
+    pub water_level: WaterLevel,
+    // When the last successful reading was taken
+    #[serde(skip)]
+    pub ec_updated: Option<Instant>,
+    #[serde(skip)]
+    pub ph_updated: Option<Instant>,
+    #[serde(skip)]
+    pub water_level_updated: Option<Instant>,
+    // Latched when a leak probe trips, everything stays off until it's reset through the API
+    pub emergency: Option<Emergency>,
+    // Set when dosing looks unsafe, only cleared through the API
+    pub dosing_lockout: Option<DosingLockout>,
+    // mL dispensed by each dosing pump since its bottle was refilled
+    pub pump_dispensed_ml: [f32; PUMP_COUNT],
+    pub pump_faults: [Option<MotorFault>; PUMP_COUNT],
+    pub light_percent: u8,
+    // Set while the light is under manual control
+    pub light_overridden: bool,
+    pub irrigation_pump_on: bool,
+    // Set while the reservoir is being filled and the fresh water mixes in
+    pub topping_up: bool,
+    pub last_top_up_litres: f32,
+    // The last fill ran out of time before the reservoir was full
+    pub top_up_timed_out: bool,
 }
 
 impl HydroponicState {
@@ -26,12 +62,25 @@ impl HydroponicState {
         HydroponicState {
             ec: EcState::Unknown,
             ph: PhState::Unknown,
-            water_level: WaterLevelState::Unknown,
+            water_level: WaterLevel::UNKNOWN,
+            ec_updated: None,
+            ph_updated: None,
+            water_level_updated: None,
+            emergency: None,
+            dosing_lockout: None,
+            pump_dispensed_ml: [0.0; PUMP_COUNT],
+            pump_faults: [None; PUMP_COUNT],
+            light_percent: 0,
+            light_overridden: false,
+            irrigation_pump_on: false,
+            topping_up: false,
+            last_top_up_litres: 0.0,
+            top_up_timed_out: false,
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
 pub enum EcState {
     #[default]
     Unknown,
@@ -40,7 +89,7 @@ pub enum EcState {
     Low(f32),
 }
 
-#[derive(Debug, Serialize, Deserialize, Copy, Clone, Default)]
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Default)]
 pub enum PhState {
     #[default]
     Unknown,
@@ -49,82 +98,146 @@ pub enum PhState {
     Low(f32),
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
-pub enum WaterLevelState {
-    #[default]
-    Unknown,
-    Good,
-    Low,
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum DosingLockout {
+    // pH moved away from the target after a dose
+    PhWrongWay,
+    // EC didn't rise as expected after dosing nutrients
+    EcNoRise,
 }
 
-const UPPER_LIMIT_PH: f32 = 7.4;
-const LOWER_LIMIT_PH: f32 = 5.3;
-const UPPER_LIMIT_EC: f32 = 1200.0;
-const LOWER_LIMIT_EC: f32 = 1000.0;
+// How often the water level is read while the reservoir is filling
+const FILLING_INTERVAL_SECS: u64 = 1;
 
 pub type I2c1Bus = Mutex<NoopRawMutex, I2c<'static, I2C1, Async>>;
 
 pub static MACHINE_STATE: Mutex<CriticalSectionRawMutex, HydroponicState> =
     Mutex::new(HydroponicState::initial_state());
 
+/// Changes the machine state and publishes an event for whatever changed. Anything that
+/// writes the state goes through here so event subscribers don't miss changes.
+pub async fn update_state<R>(change: impl FnOnce(&mut HydroponicState) -> R) -> R {
+    let mut state = MACHINE_STATE.lock().await;
+    let before = *state;
+    let result = change(&mut state);
+    events::publish_changes(&before, &state);
+    result
+}
+
+/// Wakes the EC task for a reading now, instead of at the next interval
+pub static EC_RECHECK: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Wakes the water level task for a reading now, instead of at the next interval
+pub static LEVEL_RECHECK: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
 #[embassy_executor::task]
 pub async fn update_ec_state_task(i2c: &'static I2c1Bus) {
-    // TODO SET TO CORRECT ADDR
-    let mut ec_board = EzoBoard::new(I2cDevice::new(i2c), 0x20);
+    let address = CONFIG.lock().await.ec_address;
+    let mut ec_board = EzoBoard::new(I2cDevice::new(i2c), address);
 
     loop {
         info!("Reading EC...");
-        if let Ok(reading) = ec_board.send_and_recieve(EzoCommand::Read).await {
-            let reading = reading.parse::<f32>().unwrap();
-            if reading > UPPER_LIMIT_EC {
-                MACHINE_STATE.lock().await.ec = EcState::High(reading);
-            } else if reading < LOWER_LIMIT_EC {
-                MACHINE_STATE.lock().await.ec = EcState::Low(reading);
-            } else {
-                MACHINE_STATE.lock().await.ec = EcState::Good(reading);
+        match ec_board.send_and_recieve(EzoCommand::Read).await {
+            Ok(reading) => {
+                let reading = reading.parse::<f32>().unwrap();
+                let config = *CONFIG.lock().await;
+                let ec = if reading > config.ec_upper {
+                    EcState::High(reading)
+                } else if reading < config.ec_lower {
+                    EcState::Low(reading)
+                } else {
+                    EcState::Good(reading)
+                };
+                update_state(|state| {
+                    state.ec = ec;
+                    state.ec_updated = Some(Instant::now());
+                })
+                .await;
+            }
+            Err(e) => {
+                warn!("Failed to read EC: {:?}", e);
+                metrics::count_ezo_error(EzoSensor::Ec);
             }
         }
 
-        // Waits 3 minutes before reading again
-        Timer::after_secs(180).await;
+        // Waits before reading again (3 minutes by default)
+        let interval = CONFIG.lock().await.ec_interval_secs as u64;
+        select(Timer::after_secs(interval), EC_RECHECK.wait()).await;
     }
 }
 
 #[embassy_executor::task]
 pub async fn update_ph_state_task(i2c: &'static I2c1Bus) {
-    // TODO: SET TO CORRECT ADDR
-    let mut ph_board = EzoBoard::new(I2cDevice::new(i2c), 0x21);
+    let address = CONFIG.lock().await.ph_address;
+    let mut ph_board = EzoBoard::new(I2cDevice::new(i2c), address);
 
     loop {
         info!("Reading pH...");
-        if let Ok(reading) = ph_board.send_and_recieve(EzoCommand::Read).await {
-            let reading = reading.parse::<f32>().unwrap();
-            if reading > UPPER_LIMIT_PH {
-                MACHINE_STATE.lock().await.ph = PhState::High(reading);
-            } else if reading < LOWER_LIMIT_PH {
-                MACHINE_STATE.lock().await.ph = PhState::Low(reading);
-            } else {
-                MACHINE_STATE.lock().await.ph = PhState::Good(reading);
+        match ph_board.send_and_recieve(EzoCommand::Read).await {
+            Ok(reading) => {
+                let reading = reading.parse::<f32>().unwrap();
+                let config = *CONFIG.lock().await;
+                let ph = if reading > config.ph_upper {
+                    PhState::High(reading)
+                } else if reading < config.ph_lower {
+                    PhState::Low(reading)
+                } else {
+                    PhState::Good(reading)
+                };
+                update_state(|state| {
+                    state.ph = ph;
+                    state.ph_updated = Some(Instant::now());
+                })
+                .await;
+            }
+            Err(e) => {
+                warn!("Failed to read pH: {:?}", e);
+                metrics::count_ezo_error(EzoSensor::Ph);
             }
         }
 
-        // Waits 3 mins before reading again
-        Timer::after_secs(180).await;
+        // Waits before reading again (3 mins by default)
+        Timer::after_secs(CONFIG.lock().await.ph_interval_secs as u64).await;
     }
 }
 
 #[embassy_executor::task]
-pub async fn update_water_lvl_state_task(pin: Input<'static>) {
+pub async fn update_water_lvl_state_task(
+    mut floats: FloatSwitches,
+    mut continuous: Option<ContinuousLevel>,
+) {
     loop {
         info!("Reading water level...");
-        // Pin will be high is level is good
-        if pin.is_high() {
-            MACHINE_STATE.lock().await.water_level = WaterLevelState::Good;
+        let config = *CONFIG.lock().await;
+        let height_mm = match continuous.as_mut() {
+            Some(sensor) => sensor.read_height_mm(&config).await,
+            None => None,
+        };
+        let level = WaterLevel::from_readings(floats.band(), height_mm, &config);
+        let topping_up = update_state(|state| {
+            state.water_level = level;
+            state.water_level_updated = Some(Instant::now());
+            state.topping_up
+        })
+        .await;
+
+        // Float switch changes are picked up as they happen, the interval only re-confirms
+        // the level (10 minutes by default). A continuous sensor has no edges, so it's read
+        // more often while the top-up needs to see the level rise.
+        let interval = if topping_up && continuous.is_some() {
+            FILLING_INTERVAL_SECS
         } else {
-            MACHINE_STATE.lock().await.water_level = WaterLevelState::Low;
+            config.water_level_interval_secs as u64
+        };
+        let wake = select3(
+            Timer::after_secs(interval),
+            LEVEL_RECHECK.wait(),
+            floats.wait_for_edge(),
+        )
+        .await;
+        if let Either3::Third(()) = wake {
+            let debounce = Duration::from_millis(config.float_debounce_ms as u64);
+            floats.settle(debounce).await;
         }
-
-        // Waits 10 minutes before reading again
-        Timer::after_secs(600).await;
     }
 }
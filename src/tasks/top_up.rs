@@ -0,0 +1,200 @@
+// Refills the reservoir with fresh water when the level drops below the normal mark, or below
+// the high mark when there is no normal switch
+use core::pin::pin;
+
+use embassy_futures::select::{Either, Either3, select, select3};
+use embassy_rp::gpio::Output;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+use log::*;
+
+use crate::{
+    config::CONFIG,
+    hardware::actuator::{Actuator, Interlocked, SolenoidValve, emergency_stopped},
+    tasks::{
+        dose::WATER_ADDED,
+        maintenance::in_maintenance,
+        state::{EC_RECHECK, LEVEL_RECHECK, MACHINE_STATE, update_state},
+    },
+};
+
+pub type FillValve = Interlocked<SolenoidValve<Output<'static>>>;
+
+const POLL_INTERVAL_SECS: u64 = 10;
+const FILL_CHECK_INTERVAL: Duration = Duration::from_millis(200);
+
+// Set through the API once whatever stopped the last fill has been fixed
+static TOP_UP_RESET: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Lets the top-up run again after a fill timed out
+pub fn reset_top_up() {
+    TOP_UP_RESET.signal(());
+}
+
+/// Opens or closes the fill valve by hand, only acted on in maintenance mode
+#[derive(Debug, Clone, Copy)]
+pub enum ValveCommand {
+    Open { secs: u32 },
+    Close,
+}
+
+pub static VALVE_COMMANDS: Signal<CriticalSectionRawMutex, ValveCommand> = Signal::new();
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FillResult {
+    Full,
+    TimedOut,
+    // Emergency stop, or the valve wouldn't open
+    Stopped,
+}
+
+// Opens the valve until the level reaches the high mark or the max fill time runs out.
+// Returns how long the valve was open and why it closed.
+async fn fill(valve: &mut FillValve, max_fill: Duration) -> (Duration, FillResult) {
+    if let Err(e) = valve.set_on(true).await {
+        error!("Failed to open the fill valve: {}", e);
+        return (Duration::from_secs(0), FillResult::Stopped);
+    }
+    let start = Instant::now();
+    let mut result = FillResult::TimedOut;
+    while start.elapsed() < max_fill {
+        if MACHINE_STATE.lock().await.water_level.band.is_full() {
+            result = FillResult::Full;
+            break;
+        }
+        // The interlock closes the valve itself
+        if let Either::Second(()) =
+            select(Timer::after(FILL_CHECK_INTERVAL), valve.wait_for_stop()).await
+        {
+            result = FillResult::Stopped;
+            break;
+        }
+    }
+    let open_for = start.elapsed();
+    if let Err(e) = valve.set_on(false).await {
+        error!("Failed to close the fill valve: {}", e);
+    }
+    (open_for, result)
+}
+
+// Holds the valve open for `secs`, at most the max fill time, until the reservoir is full,
+// it's closed by hand or maintenance mode ends
+async fn manual_fill(valve: &mut FillValve, secs: u32) {
+    let max_fill_secs = CONFIG.lock().await.top_up_max_fill_secs;
+    let open_for = |secs: u32| Duration::from_secs(secs.min(max_fill_secs) as u64);
+    if let Err(e) = valve.set_on(true).await {
+        error!("Failed to open the fill valve: {}", e);
+        return;
+    }
+    info!(
+        "Fill valve opened by hand for {}s",
+        open_for(secs).as_secs()
+    );
+    update_state(|state| state.topping_up = true).await;
+    LEVEL_RECHECK.signal(());
+    let mut until = Instant::now() + open_for(secs);
+    while Instant::now() < until && in_maintenance() {
+        if MACHINE_STATE.lock().await.water_level.band.is_full() {
+            info!("Reservoir full, closing the fill valve");
+            break;
+        }
+        match select3(
+            Timer::after(FILL_CHECK_INTERVAL),
+            valve.wait_for_stop(),
+            VALVE_COMMANDS.wait(),
+        )
+        .await
+        {
+            Either3::First(()) => {}
+            Either3::Third(ValveCommand::Open { secs }) => {
+                until = Instant::now() + open_for(secs);
+            }
+            Either3::Second(()) | Either3::Third(ValveCommand::Close) => break,
+        }
+    }
+    if let Err(e) = valve.set_on(false).await {
+        error!("Failed to close the fill valve: {}", e);
+    }
+    WATER_ADDED.signal(());
+    update_state(|state| state.topping_up = false).await;
+    info!("Fill valve closed");
+}
+
+async fn handle_command(valve: &mut FillValve, command: ValveCommand) {
+    match command {
+        ValveCommand::Open { secs } if in_maintenance() => manual_fill(valve, secs).await,
+        ValveCommand::Open { .. } => {
+            warn!("The fill valve can only be opened by hand in maintenance mode");
+        }
+        // Already closed
+        ValveCommand::Close => {}
+    }
+}
+
+// Waits for `wait` to finish, acting on valve commands in the meantime so none are missed
+async fn wait_with_commands<F: Future>(valve: &mut FillValve, wait: F) -> F::Output {
+    let mut wait = pin!(wait);
+    loop {
+        match select(&mut wait, VALVE_COMMANDS.wait()).await {
+            Either::First(output) => return output,
+            Either::Second(command) => handle_command(valve, command).await,
+        }
+    }
+}
+
+#[embassy_executor::task]
+pub async fn top_up_task(mut valve: FillValve) {
+    loop {
+        if let Either::Second(command) =
+            select(Timer::after_secs(POLL_INTERVAL_SECS), VALVE_COMMANDS.wait()).await
+        {
+            handle_command(&mut valve, command).await;
+            continue;
+        }
+
+        if emergency_stopped()
+            || in_maintenance()
+            || !MACHINE_STATE.lock().await.water_level.band.needs_top_up()
+        {
+            continue;
+        }
+
+        let config = *CONFIG.lock().await;
+        info!("Water level low, topping up");
+        update_state(|state| state.topping_up = true).await;
+        // Gets the level task reading often enough to see the reservoir fill
+        LEVEL_RECHECK.signal(());
+        let max_fill = Duration::from_secs(config.top_up_max_fill_secs as u64);
+        let (open_for, result) = fill(&mut valve, max_fill).await;
+        WATER_ADDED.signal(());
+
+        let litres = open_for.as_millis() as f32 / 60_000.0 * config.top_up_litres_per_min;
+        info!("Added about {:.1} L in {}s", litres, open_for.as_secs());
+        update_state(|state| {
+            state.last_top_up_litres = litres;
+            state.top_up_timed_out = result == FillResult::TimedOut;
+        })
+        .await;
+
+        // Fresh water dilutes the nutrients, so get a new EC reading once it has mixed in
+        wait_with_commands(
+            &mut valve,
+            Timer::after_secs(config.top_up_mixing_secs as u64),
+        )
+        .await;
+        update_state(|state| state.topping_up = false).await;
+        EC_RECHECK.signal(());
+
+        if result == FillResult::TimedOut {
+            // Either the supply is off or water is going somewhere it shouldn't
+            error!(
+                "Reservoir not full after {}s, top-up disabled until reset",
+                config.top_up_max_fill_secs
+            );
+            TOP_UP_RESET.reset();
+            wait_with_commands(&mut valve, TOP_UP_RESET.wait()).await;
+            update_state(|state| state.top_up_timed_out = false).await;
+            info!("Top-up reset");
+        }
+    }
+}
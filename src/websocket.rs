@@ -0,0 +1,407 @@
+// WebSocket (RFC 6455) handshake and framing. Like http.rs it doesn't depend on anything
+// embassy. Messages have to arrive whole in a single frame, which is how browsers send
+// anything this small.
+use core::ops::Range;
+
+use heapless::{String, Vec};
+use thiserror::Error;
+
+use crate::{
+    base64,
+    http::{Method, Request},
+    sha1,
+};
+
+// Appended to the client's key before hashing it for the accept header
+const GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Length of the base64 SHA-1 in Sec-WebSocket-Accept
+pub const ACCEPT_LEN: usize = 28;
+// The key is 16 random bytes
+const KEY_LEN: usize = 16;
+// Control frames can't be fragmented or longer than this
+const MAX_CONTROL_LEN: u64 = 125;
+/// Longest frame header the server sends, for payloads up to 64 KiB
+pub const MAX_HEADER_LEN: usize = 4;
+
+/// Close codes
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_UNSUPPORTED: u16 = 1003;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl Opcode {
+    fn parse(bits: u8) -> Option<Opcode> {
+        match bits {
+            0x0 => Some(Opcode::Continuation),
+            0x1 => Some(Opcode::Text),
+            0x2 => Some(Opcode::Binary),
+            0x8 => Some(Opcode::Close),
+            0x9 => Some(Opcode::Ping),
+            0xA => Some(Opcode::Pong),
+            _ => None,
+        }
+    }
+
+    fn bits(self) -> u8 {
+        match self {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xA,
+        }
+    }
+
+    fn is_control(self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+#[derive(Debug)]
+pub struct Frame {
+    pub opcode: Opcode,
+    // Clear when more frames of the same message follow
+    pub fin: bool,
+    // Where the unmasked payload is in the buffer
+    pub payload: Range<usize>,
+    // Bytes used by this frame, anything after it belongs to the next one
+    pub len: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Error)]
+pub enum FrameError {
+    /// Not an error, more has to be read before the frame can be parsed
+    #[error("Frame is incomplete")]
+    Incomplete,
+    #[error("Invalid frame")]
+    Protocol,
+    #[error("Frame is too large")]
+    TooBig,
+}
+
+impl FrameError {
+    /// Code to close the connection with
+    pub fn close_code(&self) -> u16 {
+        match self {
+            FrameError::Incomplete | FrameError::Protocol => CLOSE_PROTOCOL_ERROR,
+            FrameError::TooBig => CLOSE_TOO_BIG,
+        }
+    }
+}
+
+/// The Sec-WebSocket-Accept value for an upgrade request, or `None` if it isn't one
+pub fn accept_key(req: &Request<'_>) -> Option<String<ACCEPT_LEN>> {
+    let has_token = |name: &str, token: &str| {
+        req.header(name)
+            .is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+    };
+    if req.method != Method::Get
+        || !has_token("Upgrade", "websocket")
+        || !has_token("Connection", "upgrade")
+        || req.header("Sec-WebSocket-Version").map(str::trim) != Some("13")
+    {
+        return None;
+    }
+    let key = req.header("Sec-WebSocket-Key")?.trim();
+    if base64::decode::<KEY_LEN>(key.as_bytes())?.len() != KEY_LEN {
+        return None;
+    }
+    base64::encode(&sha1::digest(&[key.as_bytes(), GUID]))
+}
+
+/// Parses the frame at the start of `buf` and unmasks its payload in place. `max_len` is the
+/// size of the read buffer, a frame that can't fit in it is rejected.
+pub fn parse_frame(buf: &mut [u8], max_len: usize) -> Result<Frame, FrameError> {
+    if buf.len() < 2 {
+        return Err(FrameError::Incomplete);
+    }
+    let fin = buf[0] & 0x80 != 0;
+    // No extensions are agreed to, so the reserved bits have to be clear
+    if buf[0] & 0x70 != 0 {
+        return Err(FrameError::Protocol);
+    }
+    let opcode = Opcode::parse(buf[0] & 0x0F).ok_or(FrameError::Protocol)?;
+    // Everything a client sends is masked
+    if buf[1] & 0x80 == 0 {
+        return Err(FrameError::Protocol);
+    }
+    let (payload_len, mask_start) = match buf[1] & 0x7F {
+        126 if buf.len() < 4 => return Err(FrameError::Incomplete),
+        126 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+        127 if buf.len() < 10 => return Err(FrameError::Incomplete),
+        127 => {
+            let mut len = [0; 8];
+            len.copy_from_slice(&buf[2..10]);
+            (u64::from_be_bytes(len), 10)
+        }
+        len => (len as u64, 2),
+    };
+    if opcode.is_control() && (!fin || payload_len > MAX_CONTROL_LEN) {
+        return Err(FrameError::Protocol);
+    }
+    let start = mask_start + 4;
+    // A 64 bit length can be anything, so it mustn't overflow
+    if (start as u64).saturating_add(payload_len) > max_len as u64 {
+        return Err(FrameError::TooBig);
+    }
+    let len = start + payload_len as usize;
+    if buf.len() < len {
+        return Err(FrameError::Incomplete);
+    }
+
+    let mut mask = [0; 4];
+    mask.copy_from_slice(&buf[mask_start..start]);
+    for (i, b) in buf[start..len].iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+    Ok(Frame {
+        opcode,
+        fin,
+        payload: start..len,
+        len,
+    })
+}
+
+/// Header for a single unmasked frame from the server
+pub fn frame_header(opcode: Opcode, payload_len: usize) -> Vec<u8, MAX_HEADER_LEN> {
+    let first = 0x80 | opcode.bits();
+    let mut header = Vec::new();
+    let result = if payload_len < 126 {
+        header.extend_from_slice(&[first, payload_len as u8])
+    } else {
+        let len = u16::try_from(payload_len).expect("FRAME TOO LARGE!");
+        let [high, low] = len.to_be_bytes();
+        header.extend_from_slice(&[first, 126, high, low])
+    };
+    result.expect("BUFFER TOO SMALL!");
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http;
+
+    const MAX_LEN: usize = 512;
+    const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+    // A masked client frame, with the length in the shortest form unless `len_bits` says
+    // otherwise
+    fn client_frame(first: u8, payload: &[u8], len_bits: Option<u8>) -> Vec<u8, 1024> {
+        let mut frame = Vec::new();
+        frame.push(first).unwrap();
+        match len_bits.unwrap_or(match payload.len() {
+            0..126 => payload.len() as u8,
+            126..65536 => 126,
+            _ => 127,
+        }) {
+            126 => {
+                frame.push(0x80 | 126).unwrap();
+                let len = payload.len() as u16;
+                frame.extend_from_slice(&len.to_be_bytes()).unwrap();
+            }
+            127 => {
+                frame.push(0x80 | 127).unwrap();
+                let len = payload.len() as u64;
+                frame.extend_from_slice(&len.to_be_bytes()).unwrap();
+            }
+            len => frame.push(0x80 | len).unwrap(),
+        }
+        frame.extend_from_slice(&MASK).unwrap();
+        for (i, b) in payload.iter().enumerate() {
+            frame.push(b ^ MASK[i % 4]).unwrap();
+        }
+        frame
+    }
+
+    #[test]
+    fn accepts_the_rfc_handshake() {
+        let req = http::parse(
+            b"GET /api/ws HTTP/1.1\r\nHost: pico\r\nUpgrade: websocket\r\n\
+              Connection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+              Sec-WebSocket-Version: 13\r\n\r\n",
+            MAX_LEN,
+        )
+        .unwrap();
+        assert_eq!(accept_key(&req).unwrap(), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn ignores_requests_that_are_not_upgrades() {
+        let req = http::parse(
+            b"GET /api/ws HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 8\r\n\r\n",
+            MAX_LEN,
+        )
+        .unwrap();
+        assert_eq!(accept_key(&req), None);
+        let req = http::parse(b"GET /api/ws HTTP/1.1\r\n\r\n", MAX_LEN).unwrap();
+        assert_eq!(accept_key(&req), None);
+    }
+
+    #[test]
+    fn unmasks_the_rfc_example() {
+        // A masked "Hello" from RFC 6455 section 5.7
+        let mut buf = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let frame = parse_frame(&mut buf, MAX_LEN).unwrap();
+        assert_eq!(frame.opcode, Opcode::Text);
+        assert!(frame.fin);
+        assert_eq!(&buf[frame.payload], b"Hello");
+        assert_eq!(frame.len, buf.len());
+    }
+
+    #[test]
+    fn rejects_unmasked_client_frames() {
+        let mut buf = [0x81, 0x05, b'H', b'e', b'l', b'l', b'o'];
+        assert_eq!(
+            parse_frame(&mut buf, MAX_LEN).unwrap_err(),
+            FrameError::Protocol
+        );
+    }
+
+    #[test]
+    fn reads_extended_lengths() {
+        let payload = [b'x'; 300];
+        let mut buf = client_frame(0x82, &payload, None);
+        assert_eq!(buf[1], 0x80 | 126);
+        let frame = parse_frame(&mut buf, MAX_LEN).unwrap();
+        assert_eq!(frame.opcode, Opcode::Binary);
+        assert_eq!(&buf[frame.payload], &payload[..]);
+
+        // Browsers only use the 64 bit length for huge frames, but it's valid for any
+        let mut buf = client_frame(0x82, &payload, Some(127));
+        let frame = parse_frame(&mut buf, MAX_LEN).unwrap();
+        assert_eq!(frame.payload, 14..314);
+        assert_eq!(&buf[frame.payload], &payload[..]);
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_a_split_read() {
+        for len_bits in [None, Some(126), Some(127)] {
+            let mut buf = client_frame(0x81, b"status", len_bits);
+            for split in 0..buf.len() {
+                assert_eq!(
+                    parse_frame(&mut buf[..split], MAX_LEN).unwrap_err(),
+                    FrameError::Incomplete
+                );
+            }
+            let frame = parse_frame(&mut buf, MAX_LEN).unwrap();
+            assert_eq!(&buf[frame.payload], b"status");
+        }
+    }
+
+    #[test]
+    fn rejects_frames_too_large_for_the_buffer() {
+        let mut buf = client_frame(0x81, &[b'x'; 600], None);
+        assert_eq!(
+            parse_frame(&mut buf, MAX_LEN).unwrap_err(),
+            FrameError::TooBig
+        );
+        // Known from the header alone, before the payload is read
+        assert_eq!(
+            parse_frame(&mut buf[..8], MAX_LEN).unwrap_err(),
+            FrameError::TooBig
+        );
+
+        let mut buf = [
+            0x82,
+            0x80 | 127,
+            0xff,
+            0xff,
+            0xff,
+            0xff,
+            0xff,
+            0xff,
+            0xff,
+            0xff,
+        ];
+        assert_eq!(
+            parse_frame(&mut buf, MAX_LEN).unwrap_err(),
+            FrameError::TooBig
+        );
+    }
+
+    #[test]
+    fn keeps_fragments_apart() {
+        let mut buf: Vec<u8, 1024> = Vec::new();
+        buf.extend_from_slice(&client_frame(0x01, b"sta", None))
+            .unwrap();
+        buf.extend_from_slice(&client_frame(0x80, b"tus", None))
+            .unwrap();
+
+        let first = parse_frame(&mut buf, MAX_LEN).unwrap();
+        assert_eq!(first.opcode, Opcode::Text);
+        assert!(!first.fin);
+        assert_eq!(&buf[first.payload], b"sta");
+
+        let rest = &mut buf[first.len..];
+        let second = parse_frame(rest, MAX_LEN).unwrap();
+        assert_eq!(second.opcode, Opcode::Continuation);
+        assert!(second.fin);
+        assert_eq!(&rest[second.payload], b"tus");
+        assert_eq!(second.len, rest.len());
+    }
+
+    #[test]
+    fn parses_control_frames() {
+        let mut buf = client_frame(0x88, &CLOSE_NORMAL.to_be_bytes(), None);
+        let frame = parse_frame(&mut buf, MAX_LEN).unwrap();
+        assert_eq!(frame.opcode, Opcode::Close);
+        assert_eq!(&buf[frame.payload], &[0x03, 0xe8]);
+
+        let mut buf = client_frame(0x89, b"are you there", None);
+        let frame = parse_frame(&mut buf, MAX_LEN).unwrap();
+        assert_eq!(frame.opcode, Opcode::Ping);
+        assert_eq!(&buf[frame.payload], b"are you there");
+    }
+
+    #[test]
+    fn rejects_invalid_control_frames() {
+        // Fragmented
+        let mut buf = client_frame(0x09, b"ping", None);
+        assert_eq!(
+            parse_frame(&mut buf, MAX_LEN).unwrap_err(),
+            FrameError::Protocol
+        );
+        // Too long
+        let mut buf = client_frame(0x89, &[0; 126], None);
+        assert_eq!(
+            parse_frame(&mut buf, MAX_LEN).unwrap_err(),
+            FrameError::Protocol
+        );
+    }
+
+    #[test]
+    fn rejects_reserved_bits_and_opcodes() {
+        let mut buf = client_frame(0xc1, b"x", None);
+        assert_eq!(
+            parse_frame(&mut buf, MAX_LEN).unwrap_err(),
+            FrameError::Protocol
+        );
+        let mut buf = client_frame(0x83, b"x", None);
+        assert_eq!(
+            parse_frame(&mut buf, MAX_LEN).unwrap_err(),
+            FrameError::Protocol
+        );
+    }
+
+    #[test]
+    fn writes_the_shortest_header() {
+        assert_eq!(frame_header(Opcode::Text, 5), [0x81, 5]);
+        assert_eq!(frame_header(Opcode::Text, 300), [0x81, 126, 0x01, 0x2c]);
+        assert_eq!(frame_header(Opcode::Close, 2), [0x88, 2]);
+    }
+}